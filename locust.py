@@ -1,6 +1,7 @@
 from locust import HttpUser, TaskSet, SequentialTaskSet, task,  between
 import uuid
 import os
+import random
 
 config_set = {
    'version' : 1,
@@ -11,6 +12,11 @@ config_set = {
 class PaymentsBehaviour(SequentialTaskSet):
     payment_id = None
     attempt_version = [None] * 1
+
+    def on_start(self):
+        # comma separated merchants, each simulated user picks one to model multi-tenant traffic
+        merchants = os.environ.get('MERCHANT_IDS', 'kaps').split(',')
+        self.client.headers['x-merchant-id'] = random.choice(merchants)
     
     def gen(self):
         config_set['version'] += 1
@@ -0,0 +1,499 @@
+use crate::errors::StoreError;
+use crate::models::{PaymentAttemptInterface, PaymentImportInterface, PaymentIntentInterface};
+use crate::store::{create_backend, Init, MigrationStatus, RedisClient, StorageInterface};
+use crate::types::*;
+use fred::prelude::KeysInterface;
+use fred::types::Expiration;
+use std::env;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Serialized records with the instant they expire, least recently used evicted first.
+type LocalEntries = lru::LruCache<String, (Instant, Vec<u8>)>;
+
+/// Where `CachedStore` keeps serialized records: a redis server shared by every instance or
+/// an LRU map local to this process.
+#[derive(Clone)]
+pub enum Cache {
+    Redis(fred::prelude::RedisPool),
+    Local(Arc<Mutex<LocalEntries>>),
+}
+
+impl Cache {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StoreError> {
+        match self {
+            Self::Redis(pool) => Ok(pool.next().get::<Option<Vec<u8>>, _>(key).await?),
+            Self::Local(entries) => {
+                let mut entries = entries.lock().unwrap_or_else(|err| err.into_inner());
+                match entries.get(key) {
+                    Some((expires_at, value)) if *expires_at > Instant::now() => {
+                        Ok(Some(value.clone()))
+                    }
+                    Some(_) => {
+                        entries.pop(key);
+                        Ok(None)
+                    }
+                    None => Ok(None),
+                }
+            }
+        }
+    }
+
+    async fn set(&self, key: String, value: Vec<u8>, ttl: Duration) -> Result<(), StoreError> {
+        match self {
+            Self::Redis(pool) => {
+                pool.next()
+                    .set::<(), _, _>(
+                        key,
+                        value.as_slice(),
+                        Some(Expiration::PX(ttl.as_millis() as i64)),
+                        None,
+                        false,
+                    )
+                    .await?
+            }
+            Self::Local(entries) => {
+                entries
+                    .lock()
+                    .unwrap_or_else(|err| err.into_inner())
+                    .put(key, (Instant::now() + ttl, value));
+            }
+        }
+        Ok(())
+    }
+
+    async fn invalidate(&self, keys: Vec<String>) -> Result<(), StoreError> {
+        match self {
+            Self::Redis(pool) => pool.next().del::<(), _>(keys).await?,
+            Self::Local(entries) => {
+                let mut entries = entries.lock().unwrap_or_else(|err| err.into_inner());
+                for key in keys {
+                    entries.pop(&key);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Read-through cache in front of another backend.
+///
+/// `retrieve_intent` and `retrieve_all` are answered from the cache while the entry is younger
+/// than `ttl` and fill it on a miss. Writes go to the primary and then drop the payment's
+/// entries, so a read racing a write can cache the old record for at most `ttl`. Cache errors
+/// fall through to the primary.
+pub struct CachedStore {
+    pub primary: Box<dyn StorageInterface>,
+    pub cache: Cache,
+    pub ttl: Duration,
+}
+
+impl Clone for CachedStore {
+    fn clone(&self) -> Self {
+        Self {
+            primary: dyn_clone::clone_box(&*self.primary),
+            cache: self.cache.clone(),
+            ttl: self.ttl,
+        }
+    }
+}
+
+/// `STORE_BACKEND=cached`: `CACHE_PRIMARY` (default `cassandra`) behind a `CACHE_BACKEND`
+/// cache, either `memory` (default, an LRU of `CACHE_CAPACITY` entries) or `redis`, with
+/// entries kept for `CACHE_TTL_MS` (default 60000).
+pub async fn cached() -> Result<CachedStore, Box<dyn std::error::Error>> {
+    let primary = env::var("CACHE_PRIMARY").unwrap_or_else(|_| "cassandra".to_owned());
+    if primary == "cached" {
+        return Err("CACHE_PRIMARY cannot be cached".into());
+    }
+    let ttl = env::var("CACHE_TTL_MS").unwrap_or_else(|_| "60000".to_owned());
+    let cache = match env::var("CACHE_BACKEND")
+        .unwrap_or_else(|_| "memory".to_owned())
+        .as_str()
+    {
+        "redis" => Cache::Redis(RedisClient::new().await?.pool),
+        "memory" => {
+            let capacity = env::var("CACHE_CAPACITY").unwrap_or_else(|_| "10000".to_owned());
+            let capacity = NonZeroUsize::new(capacity.parse()?).ok_or("CACHE_CAPACITY is 0")?;
+            Cache::Local(Arc::new(Mutex::new(lru::LruCache::new(capacity))))
+        }
+        other => return Err(format!("unknown CACHE_BACKEND {}", other).into()),
+    };
+
+    Ok(CachedStore {
+        primary: Box::pin(create_backend(&primary)).await?,
+        cache,
+        ttl: Duration::from_millis(ttl.parse()?),
+    })
+}
+
+/// The merchant id is prefixed with its length, so ids containing `_` cannot run into
+/// another merchant's entry.
+fn intent_key(merchant_id: &str, payment_id: &str) -> String {
+    format!(
+        "cache_pi_{}_{}_{}",
+        merchant_id.len(),
+        merchant_id,
+        payment_id
+    )
+}
+
+fn attempts_key(merchant_id: &str, payment_id: &str) -> String {
+    format!(
+        "cache_pa_{}_{}_{}",
+        merchant_id.len(),
+        merchant_id,
+        payment_id
+    )
+}
+
+impl CachedStore {
+    async fn cached<T>(&self, model_name: &'static str, key: &str) -> Option<T>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        let result = match self.cache.get(key).await {
+            Ok(Some(value)) => match serde_json::from_slice(&value) {
+                Ok(record) => {
+                    record_lookup(model_name, "hit");
+                    return Some(record);
+                }
+                Err(err) => {
+                    eprintln!("dropping undecodable cache entry {}: {}", key, err);
+                    "error"
+                }
+            },
+            Ok(None) => "miss",
+            Err(err) => {
+                eprintln!("cache lookup {} failed: {}", key, err);
+                "error"
+            }
+        };
+        record_lookup(model_name, result);
+        None
+    }
+
+    async fn fill<T: serde::Serialize>(&self, key: String, record: &T) {
+        let result = match serde_json::to_vec(record) {
+            Ok(value) => self.cache.set(key, value, self.ttl).await,
+            Err(err) => Err(err.into()),
+        };
+        if let Err(err) = result {
+            eprintln!("cache fill failed: {}", err);
+        }
+    }
+
+    /// Drops the cached entries of a payment after a write. A failure leaves them to expire
+    /// with the TTL, the write itself went through.
+    async fn invalidate(&self, keys: Vec<String>) {
+        if let Err(err) = self.cache.invalidate(keys).await {
+            metrics::counter!("cache_invalidation_errors").increment(1);
+            eprintln!("cache invalidation failed: {}", err);
+        }
+    }
+}
+
+fn record_lookup(model_name: &'static str, result: &'static str) {
+    metrics::counter!(
+        "cache_lookups",
+        &[("model", model_name), ("result", result)]
+    )
+    .increment(1);
+}
+
+#[async_trait::async_trait]
+impl PaymentIntentInterface for CachedStore {
+    async fn create_intent(&self, payment_intent: PaymentIntent) -> Result<(), StoreError> {
+        // misses are not cached, so nothing can be held for an intent that did not exist
+        self.primary.create_intent(payment_intent).await
+    }
+
+    async fn retrieve_intent<'a>(
+        &self,
+        merchant_id: &'a str,
+        payment_id: &'a str,
+    ) -> Result<PaymentIntent, StoreError> {
+        let key = intent_key(merchant_id, payment_id);
+        if let Some(payment_intent) = self.cached("payment_intent", &key).await {
+            return Ok(payment_intent);
+        }
+        let payment_intent = self
+            .primary
+            .retrieve_intent(merchant_id, payment_id)
+            .await?;
+        self.fill(key, &payment_intent).await;
+        Ok(payment_intent)
+    }
+
+    async fn update_intent<'a>(
+        &self,
+        merchant_id: &'a str,
+        payment_id: &'a str,
+        expected_version: i64,
+        payment_intent_update: PaymentIntentUpdate,
+    ) -> Result<(), StoreError> {
+        let result = self
+            .primary
+            .update_intent(
+                merchant_id,
+                payment_id,
+                expected_version,
+                payment_intent_update,
+            )
+            .await;
+        // dropped whatever the outcome, a conflict means the cached copy is stale too
+        self.invalidate(vec![intent_key(merchant_id, payment_id)])
+            .await;
+        result
+    }
+
+    async fn update_intent_if_status<'a>(
+        &self,
+        merchant_id: &'a str,
+        payment_id: &'a str,
+        expected_version: i64,
+        expected_status: IntentStatus,
+        payment_intent_update: PaymentIntentUpdate,
+    ) -> Result<(), StoreError> {
+        let result = self
+            .primary
+            .update_intent_if_status(
+                merchant_id,
+                payment_id,
+                expected_version,
+                expected_status,
+                payment_intent_update,
+            )
+            .await;
+        self.invalidate(vec![intent_key(merchant_id, payment_id)])
+            .await;
+        result
+    }
+}
+
+#[async_trait::async_trait]
+impl PaymentAttemptInterface for CachedStore {
+    async fn create_attempt(&self, payment_attempt: PaymentAttempt) -> Result<(), StoreError> {
+        // creating an attempt also moves the intent's active attempt and count
+        let keys = vec![
+            intent_key(&payment_attempt.merchant_id, &payment_attempt.payment_id),
+            attempts_key(&payment_attempt.merchant_id, &payment_attempt.payment_id),
+        ];
+        let result = self.primary.create_attempt(payment_attempt).await;
+        self.invalidate(keys).await;
+        result
+    }
+
+    async fn retrieve_all<'a>(
+        &self,
+        merchant_id: &'a str,
+        payment_id: &'a str,
+    ) -> Result<Vec<PaymentAttempt>, StoreError> {
+        let key = attempts_key(merchant_id, payment_id);
+        if let Some(payment_attempts) = self.cached("payment_attempt", &key).await {
+            return Ok(payment_attempts);
+        }
+        let payment_attempts = self.primary.retrieve_all(merchant_id, payment_id).await?;
+        self.fill(key, &payment_attempts).await;
+        Ok(payment_attempts)
+    }
+
+    async fn update_attempt<'a>(
+        &self,
+        merchant_id: &'a str,
+        payment_id: &'a str,
+        attempt_id: &'a str,
+        expected_version: i64,
+        payment_attempt_update: PaymentAttemptUpdate,
+    ) -> Result<(), StoreError> {
+        let result = self
+            .primary
+            .update_attempt(
+                merchant_id,
+                payment_id,
+                attempt_id,
+                expected_version,
+                payment_attempt_update,
+            )
+            .await;
+        self.invalidate(vec![attempts_key(merchant_id, payment_id)])
+            .await;
+        result
+    }
+
+    async fn update_attempt_if_status<'a>(
+        &self,
+        merchant_id: &'a str,
+        payment_id: &'a str,
+        attempt_id: &'a str,
+        expected_version: i64,
+        expected_status: AttemptStatus,
+        payment_attempt_update: PaymentAttemptUpdate,
+    ) -> Result<(), StoreError> {
+        let result = self
+            .primary
+            .update_attempt_if_status(
+                merchant_id,
+                payment_id,
+                attempt_id,
+                expected_version,
+                expected_status,
+                payment_attempt_update,
+            )
+            .await;
+        self.invalidate(vec![attempts_key(merchant_id, payment_id)])
+            .await;
+        result
+    }
+}
+
+#[async_trait::async_trait]
+impl PaymentImportInterface for CachedStore {
+    async fn import_payment(
+        &self,
+        payment_intent: PaymentIntent,
+        payment_attempts: Vec<PaymentAttempt>,
+    ) -> Result<(), StoreError> {
+        let keys = vec![
+            intent_key(&payment_intent.merchant_id, &payment_intent.payment_id),
+            attempts_key(&payment_intent.merchant_id, &payment_intent.payment_id),
+        ];
+        let result = self
+            .primary
+            .import_payment(payment_intent, payment_attempts)
+            .await;
+        self.invalidate(keys).await;
+        result
+    }
+}
+
+#[async_trait::async_trait]
+impl Init for CachedStore {
+    async fn prepare(&self) -> Result<(), StoreError> {
+        self.primary.prepare().await
+    }
+
+    async fn schema_status(&self) -> Result<Vec<MigrationStatus>, StoreError> {
+        self.primary.schema_status().await
+    }
+}
+
+impl StorageInterface for CachedStore {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::InMemoryStore;
+
+    /// A cached in-memory store along with a handle on the same data that skips the cache.
+    fn stores(ttl: Duration) -> (CachedStore, InMemoryStore) {
+        let primary = InMemoryStore::new();
+        let capacity = NonZeroUsize::new(16).unwrap();
+        let cached = CachedStore {
+            primary: Box::new(primary.clone()),
+            cache: Cache::Local(Arc::new(Mutex::new(lru::LruCache::new(capacity)))),
+            ttl,
+        };
+        (cached, primary)
+    }
+
+    fn describe(description: &str) -> PaymentIntentUpdate {
+        PaymentIntentUpdate {
+            description: Some(description.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    async fn create_payment(primary: &InMemoryStore) {
+        primary
+            .create_intent(PaymentIntent::new("p1".to_owned(), "m1".to_owned()))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn intent_is_served_from_cache_until_invalidated() {
+        let (cached, primary) = stores(Duration::from_secs(60));
+        create_payment(&primary).await;
+        assert_eq!(cached.retrieve_intent("m1", "p1").await.unwrap().version, 1);
+
+        // written behind the cache's back, so a hit still answers with the old record
+        primary
+            .update_intent("m1", "p1", 1, describe("behind"))
+            .await
+            .unwrap();
+        assert_eq!(cached.retrieve_intent("m1", "p1").await.unwrap().version, 1);
+
+        cached
+            .update_intent("m1", "p1", 2, describe("through"))
+            .await
+            .unwrap();
+        let payment_intent = cached.retrieve_intent("m1", "p1").await.unwrap();
+        assert_eq!(payment_intent.version, 3);
+        assert_eq!(payment_intent.description.as_deref(), Some("through"));
+    }
+
+    #[tokio::test]
+    async fn misses_are_not_cached() {
+        let (cached, primary) = stores(Duration::from_secs(60));
+        assert!(matches!(
+            cached.retrieve_intent("m1", "p1").await,
+            Err(StoreError::NotFound(_))
+        ));
+        create_payment(&primary).await;
+        assert!(cached.retrieve_intent("m1", "p1").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn entries_expire_after_ttl() {
+        let (cached, primary) = stores(Duration::from_millis(20));
+        create_payment(&primary).await;
+        cached.retrieve_intent("m1", "p1").await.unwrap();
+        primary
+            .update_intent("m1", "p1", 1, describe("behind"))
+            .await
+            .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        assert_eq!(cached.retrieve_intent("m1", "p1").await.unwrap().version, 2);
+    }
+
+    #[tokio::test]
+    async fn attempt_writes_invalidate_the_payment() {
+        let (cached, primary) = stores(Duration::from_secs(60));
+        create_payment(&primary).await;
+        cached.retrieve_intent("m1", "p1").await.unwrap();
+        assert!(cached.retrieve_all("m1", "p1").await.unwrap().is_empty());
+
+        let payment_attempt = PaymentAttempt::new("p1".to_owned(), "m1".to_owned(), "1".to_owned());
+        let attempt_id = payment_attempt.attempt_id.clone();
+        cached.create_attempt(payment_attempt).await.unwrap();
+        assert_eq!(cached.retrieve_all("m1", "p1").await.unwrap().len(), 1);
+        assert_eq!(
+            cached
+                .retrieve_intent("m1", "p1")
+                .await
+                .unwrap()
+                .active_attempt_id,
+            attempt_id
+        );
+
+        let payment_attempt_update = PaymentAttemptUpdate {
+            connector: Some("updated".to_owned()),
+            ..Default::default()
+        };
+        cached
+            .update_attempt("m1", "p1", &attempt_id, 1, payment_attempt_update)
+            .await
+            .unwrap();
+        let payment_attempts = cached.retrieve_all("m1", "p1").await.unwrap();
+        assert_eq!(payment_attempts[0].connector.as_deref(), Some("updated"));
+    }
+
+    #[test]
+    fn keys_do_not_collide_across_merchants() {
+        assert_ne!(intent_key("a_1", "2"), intent_key("a", "1_2"));
+        assert_ne!(attempts_key("a_1", "2"), attempts_key("a", "1_2"));
+    }
+}
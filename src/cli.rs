@@ -0,0 +1,51 @@
+use crate::store::{create_backend, StorageInterface};
+use std::error::Error;
+
+const USAGE: &str =
+    "usage: store migrate [status|up|ddl|redis-keys] | store copy <source> <target> [resume]";
+
+/// Runs a one-off command instead of the server, e.g. `store migrate up` or
+/// `store copy redis cassandra`.
+pub async fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    match args
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        ["migrate"] | ["migrate", "status"] => {
+            print_status(create_backend("cassandra").await?.as_ref()).await
+        }
+        ["migrate", "up"] => {
+            let db = create_backend("cassandra").await?;
+            db.prepare().await?;
+            print_status(db.as_ref()).await
+        }
+        #[cfg(feature = "cassandra")]
+        ["migrate", "ddl"] => {
+            use crate::cql::CqlModel;
+            use crate::types::{PaymentAttempt, PaymentIntent};
+            // generated from the models, the starting point for the next migration file
+            println!("{}\n", PaymentIntent::create_table_cql("{keyspace}"));
+            println!("{}", PaymentAttempt::create_table_cql("{keyspace}"));
+            Ok(())
+        }
+        ["migrate", "redis-keys"] => crate::transfer::rekey_redis().await,
+        ["copy", source, target] => crate::transfer::copy(source, target, false).await,
+        ["copy", source, target, "resume"] => crate::transfer::copy(source, target, true).await,
+        _ => Err(USAGE.into()),
+    }
+}
+
+async fn print_status(db: &dyn StorageInterface) -> Result<(), Box<dyn Error>> {
+    for migration in db.schema_status().await? {
+        match migration.applied_at {
+            Some(applied_at) => println!(
+                "{:04}_{} applied at {}",
+                migration.version, migration.name, applied_at
+            ),
+            None => println!("{:04}_{} pending", migration.version, migration.name),
+        }
+    }
+    Ok(())
+}
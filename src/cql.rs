@@ -0,0 +1,404 @@
+use crate::errors::StoreError;
+use crate::types::*;
+use cassandra_cpp::{BindRustType, Row, Statement, Value};
+use std::env;
+use time::PrimitiveDateTime;
+
+/// Keyspace every statement is qualified with and the replication it is created with.
+///
+/// `CASSANDRA_KEYSPACE` names it (default `payments`). `CASSANDRA_REPLICATION` takes
+/// `DC1:3,DC2:3` for `NetworkTopologyStrategy`, without it `SimpleStrategy` with
+/// `CASSANDRA_REPLICATION_FACTOR` (default 1) is used.
+#[derive(Clone)]
+pub struct Keyspace {
+    pub name: String,
+    pub replication: String,
+}
+
+impl Keyspace {
+    pub fn from_env() -> Result<Self, StoreError> {
+        let name = env::var("CASSANDRA_KEYSPACE").unwrap_or_else(|_| "payments".to_owned());
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(StoreError::InvalidRequest(format!(
+                "invalid CASSANDRA_KEYSPACE {}",
+                name
+            )));
+        }
+
+        let replication = match env::var("CASSANDRA_REPLICATION") {
+            Ok(datacenters) => {
+                let mut replication = "{'class': 'NetworkTopologyStrategy'".to_owned();
+                for datacenter in datacenters.split(',').map(str::trim) {
+                    let (dc, factor) = datacenter
+                        .split_once(':')
+                        .and_then(|(dc, factor)| {
+                            Some((dc.trim(), factor.trim().parse::<u16>().ok()?))
+                        })
+                        .ok_or_else(|| {
+                            StoreError::InvalidRequest(format!(
+                                "invalid CASSANDRA_REPLICATION entry {}, expected DC:factor",
+                                datacenter
+                            ))
+                        })?;
+                    replication.push_str(&format!(", '{}': {}", dc, factor));
+                }
+                replication.push('}');
+                replication
+            }
+            Err(_) => {
+                let factor = env::var("CASSANDRA_REPLICATION_FACTOR")
+                    .ok()
+                    .and_then(|factor| factor.parse::<u16>().ok())
+                    .unwrap_or(1);
+                format!(
+                    "{{'class': 'SimpleStrategy', 'replication_factor': {}}}",
+                    factor
+                )
+            }
+        };
+
+        Ok(Self { name, replication })
+    }
+}
+
+/// How a rust type is stored in a single cassandra column.
+pub trait CqlColumn: Sized {
+    const CQL_TYPE: &'static str;
+
+    fn bind(&self, stmt: &mut Statement, loc: usize) -> Result<(), StoreError>;
+
+    /// Decodes a non null column value.
+    fn decode(value: &Value) -> Result<Self, StoreError>;
+
+    fn from_column(row: &Row, column: &str) -> Result<Self, StoreError> {
+        let value = row.get_column_by_name(column)?;
+        if value.is_null() {
+            return Err(StoreError::Serialization(format!(
+                "column {} is null",
+                column
+            )));
+        }
+        Self::decode(&value)
+    }
+}
+
+impl<T: CqlColumn> CqlColumn for Option<T> {
+    const CQL_TYPE: &'static str = T::CQL_TYPE;
+
+    fn bind(&self, stmt: &mut Statement, loc: usize) -> Result<(), StoreError> {
+        match self {
+            Some(val) => val.bind(stmt, loc),
+            None => {
+                stmt.bind_null(loc)?;
+                Ok(())
+            }
+        }
+    }
+
+    fn decode(value: &Value) -> Result<Self, StoreError> {
+        T::decode(value).map(Some)
+    }
+
+    fn from_column(row: &Row, column: &str) -> Result<Self, StoreError> {
+        let value = row.get_column_by_name(column)?;
+        if value.is_null() {
+            return Ok(None);
+        }
+        Self::decode(&value)
+    }
+}
+
+impl CqlColumn for String {
+    const CQL_TYPE: &'static str = "text";
+
+    fn bind(&self, stmt: &mut Statement, loc: usize) -> Result<(), StoreError> {
+        stmt.bind(loc, self.as_str())?;
+        Ok(())
+    }
+
+    fn decode(value: &Value) -> Result<Self, StoreError> {
+        Ok(value.get_string()?)
+    }
+}
+
+/// Columns bound and read through the driver's native conversions.
+macro_rules! native_column {
+    ($($ty:ty => $cql_type:literal, $get:ident);* $(;)?) => {
+        $(
+            impl CqlColumn for $ty {
+                const CQL_TYPE: &'static str = $cql_type;
+
+                fn bind(&self, stmt: &mut Statement, loc: usize) -> Result<(), StoreError> {
+                    stmt.bind(loc, *self)?;
+                    Ok(())
+                }
+
+                fn decode(value: &Value) -> Result<Self, StoreError> {
+                    Ok(value.$get()?)
+                }
+            }
+        )*
+    };
+}
+
+native_column! {
+    i64 => "bigint", get_i64;
+    i32 => "int", get_i32;
+    i16 => "smallint", get_i16;
+    bool => "boolean", get_bool;
+}
+
+/// Unit enums stored as their variant name in a `text` column, e.g. `USD`.
+macro_rules! enum_column {
+    ($($ty:ty),* $(,)?) => {
+        $(
+            impl CqlColumn for $ty {
+                const CQL_TYPE: &'static str = "text";
+
+                fn bind(&self, stmt: &mut Statement, loc: usize) -> Result<(), StoreError> {
+                    match serde_json::to_value(self)? {
+                        serde_json::Value::String(variant) => stmt.bind(loc, variant.as_str())?,
+                        other => {
+                            return Err(StoreError::Serialization(format!(
+                                "{} is not a unit variant",
+                                other
+                            )))
+                        }
+                    };
+                    Ok(())
+                }
+
+                fn decode(value: &Value) -> Result<Self, StoreError> {
+                    Ok(serde_json::from_value(serde_json::Value::String(value.get_string()?))?)
+                }
+            }
+        )*
+    };
+}
+
+enum_column!(
+    IntentStatus,
+    AttemptStatus,
+    Currency,
+    PaymentMethod,
+    CaptureMethod,
+    AuthenticationType,
+    PaymentExperience,
+    PaymentMethodType,
+);
+
+/// Free form JSON stored as its serialized bytes in a `blob` column.
+macro_rules! json_column {
+    ($($ty:ty),* $(,)?) => {
+        $(
+            impl CqlColumn for $ty {
+                const CQL_TYPE: &'static str = "blob";
+
+                fn bind(&self, stmt: &mut Statement, loc: usize) -> Result<(), StoreError> {
+                    stmt.bind_bytes(loc, serde_json::to_vec(self)?)?;
+                    Ok(())
+                }
+
+                fn decode(value: &Value) -> Result<Self, StoreError> {
+                    Ok(serde_json::from_slice(value.get_bytes()?)?)
+                }
+            }
+        )*
+    };
+}
+
+json_column!(
+    MandateDataType,
+    MandateDetails,
+    serde_json::Value,
+    Vec<serde_json::Value>,
+);
+
+/// Stored as a `timestamp`, i.e. milliseconds since the unix epoch in UTC.
+impl CqlColumn for PrimitiveDateTime {
+    const CQL_TYPE: &'static str = "timestamp";
+
+    fn bind(&self, stmt: &mut Statement, loc: usize) -> Result<(), StoreError> {
+        let millis = self.assume_utc().unix_timestamp_nanos() / 1_000_000;
+        stmt.bind(loc, millis as i64)?;
+        Ok(())
+    }
+
+    fn decode(value: &Value) -> Result<Self, StoreError> {
+        let timestamp =
+            time::OffsetDateTime::from_unix_timestamp_nanos(value.get_i64()? as i128 * 1_000_000)
+                .map_err(|err| StoreError::Serialization(err.to_string()))?;
+        Ok(PrimitiveDateTime::new(timestamp.date(), timestamp.time()))
+    }
+}
+
+/// Reads `column`, decoding a null value as `null` when the model gives one.
+pub fn from_column_or<T: CqlColumn>(
+    row: &Row,
+    column: &str,
+    null: Option<T>,
+) -> Result<T, StoreError> {
+    null_or(Option::<T>::from_column(row, column)?, null, column)
+}
+
+fn null_or<T>(value: Option<T>, null: Option<T>, column: &str) -> Result<T, StoreError> {
+    value
+        .or(null)
+        .ok_or_else(|| StoreError::Serialization(format!("column {} is null", column)))
+}
+
+/// A model mapped onto one cassandra table, generated by `cql_model!`.
+pub trait CqlModel: Sized {
+    const TABLE: &'static str;
+    const PARTITION_KEY: &'static [&'static str];
+    const CLUSTERING_KEY: &'static [&'static str];
+
+    /// Column names with their CQL types, in binding order.
+    fn columns() -> Vec<(&'static str, &'static str)>;
+
+    /// Binds every column in `columns()` order and returns the next free index.
+    fn populate_statement(&self, stmt: &mut Statement) -> Result<usize, StoreError>;
+
+    fn from_row(row: &Row) -> Result<Self, StoreError>;
+
+    fn primary_key() -> Vec<&'static str> {
+        Self::PARTITION_KEY
+            .iter()
+            .chain(Self::CLUSTERING_KEY)
+            .copied()
+            .collect()
+    }
+
+    /// Inserts the row only if it is absent, as a lightweight transaction.
+    fn insert_cql(keyspace: &str) -> String {
+        format!(
+            "{} IF NOT EXISTS;",
+            Self::batch_insert_cql(keyspace).trim_end_matches(';')
+        )
+    }
+
+    /// Unconditional insert, for logged batches which cannot hold conditions across tables.
+    fn batch_insert_cql(keyspace: &str) -> String {
+        let columns = Self::columns();
+        format!(
+            "INSERT INTO {}.{} ({}) VALUES ({});",
+            keyspace,
+            Self::TABLE,
+            columns
+                .iter()
+                .map(|(name, _)| *name)
+                .collect::<Vec<_>>()
+                .join(", "),
+            vec!["?"; columns.len()].join(", ")
+        )
+    }
+
+    /// Selects every row of a partition.
+    fn select_cql(keyspace: &str) -> String {
+        format!(
+            "SELECT * FROM {}.{} WHERE {};",
+            keyspace,
+            Self::TABLE,
+            conditions(Self::PARTITION_KEY)
+        )
+    }
+
+    /// Rows of the partitions whose token falls in `(?, ?]`, led by that token and capped by
+    /// a bound `LIMIT`, for walking the whole table one token range at a time.
+    /// `*` cannot sit next to another selector, so the columns are listed after the token.
+    fn scan_cql(keyspace: &str) -> String {
+        let token = format!("token({})", Self::PARTITION_KEY.join(", "));
+        format!(
+            "SELECT {}, {} FROM {}.{} WHERE {} > ? AND {} <= ? LIMIT ?;",
+            token,
+            Self::columns()
+                .iter()
+                .map(|(name, _)| *name)
+                .collect::<Vec<_>>()
+                .join(", "),
+            keyspace,
+            Self::TABLE,
+            token,
+            token
+        )
+    }
+
+    /// Deletes the row addressed by the full primary key, as a lightweight transaction so it
+    /// is ordered with the conditional writes on the same row.
+    fn delete_cql(keyspace: &str) -> String {
+        format!(
+            "DELETE FROM {}.{} WHERE {} IF EXISTS;",
+            keyspace,
+            Self::TABLE,
+            conditions(&Self::primary_key())
+        )
+    }
+
+    /// Updates `columns` of the row addressed by the full primary key, bound after the columns.
+    fn update_cql(keyspace: &str, columns: &[&str]) -> String {
+        let assignments = columns
+            .iter()
+            .map(|column| format!("{} = ?", column))
+            .collect::<Vec<_>>()
+            .join(", ");
+        format!(
+            "UPDATE {}.{} SET {} WHERE {};",
+            keyspace,
+            Self::TABLE,
+            assignments,
+            conditions(&Self::primary_key())
+        )
+    }
+
+    /// `update_cql` as a lightweight transaction applied only while every column in
+    /// `conditions` still holds the value bound after the primary key.
+    fn update_if_cql(keyspace: &str, columns: &[&str], conditions: &[&str]) -> String {
+        let update = Self::update_cql(keyspace, columns);
+        format!(
+            "{} IF {};",
+            update.trim_end_matches(';'),
+            self::conditions(conditions)
+        )
+    }
+
+    fn create_table_cql(keyspace: &str) -> String {
+        let columns = Self::columns()
+            .iter()
+            .map(|(name, cql_type)| format!("  {} {},\n", name, cql_type))
+            .collect::<String>();
+        let mut primary_key = format!("({})", Self::PARTITION_KEY.join(", "));
+        for column in Self::CLUSTERING_KEY {
+            primary_key.push_str(", ");
+            primary_key.push_str(column);
+        }
+        format!(
+            "CREATE TABLE IF NOT EXISTS {}.{} (\n{}  PRIMARY KEY ({})\n);",
+            keyspace,
+            Self::TABLE,
+            columns,
+            primary_key
+        )
+    }
+}
+
+fn conditions(keys: &[&str]) -> String {
+    keys.iter()
+        .map(|key| format!("{} = ?", key))
+        .collect::<Vec<_>>()
+        .join(" AND ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn null_version_reads_as_zero() {
+        assert_eq!(null_or(None, Some(0_i64), "version").unwrap(), 0);
+        assert_eq!(null_or(Some(3_i64), Some(0), "version").unwrap(), 3);
+        assert!(matches!(
+            null_or::<i64>(None, None, "amount"),
+            Err(StoreError::Serialization(_))
+        ));
+    }
+}
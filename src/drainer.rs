@@ -0,0 +1,397 @@
+use crate::errors::StoreError;
+use crate::models::Mutation;
+use crate::store::{create_backend, RedisClient, StorageInterface};
+use fred::prelude::{ClientLike, StreamsInterface};
+use std::collections::HashMap;
+use std::env;
+use std::time::Duration;
+
+impl Mutation {
+    fn op(&self) -> &'static str {
+        match self {
+            Self::CreateIntent { .. } => "create_intent",
+            Self::UpdateIntent { .. } => "update_intent",
+            Self::CreateAttempt { .. } => "create_attempt",
+            Self::UpdateAttempt { .. } => "update_attempt",
+        }
+    }
+}
+
+/// Stream and consumer group settings, read from `DRAINER_*`.
+#[derive(Clone)]
+pub struct DrainerConfig {
+    pub stream: String,
+    pub group: String,
+    pub consumer: String,
+    pub batch_size: u64,
+    /// Deliveries after which a failing entry is moved to the dead letter stream.
+    pub max_retries: u64,
+    /// How long an entry stays pending before it is delivered again.
+    pub retry_after_ms: u64,
+}
+
+impl DrainerConfig {
+    pub fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self {
+            stream: env::var("DRAINER_STREAM").unwrap_or_else(|_| "drainer_stream".to_owned()),
+            group: env::var("DRAINER_GROUP").unwrap_or_else(|_| "drainer".to_owned()),
+            // the pid is 1 in most containers, so it would give every instance the same name
+            consumer: env::var("DRAINER_CONSUMER")
+                .or_else(|_| env::var("HOSTNAME"))
+                .unwrap_or_else(|_| format!("drainer_{:016x}", rand::random::<u64>())),
+            batch_size: env::var("DRAINER_BATCH_SIZE")
+                .unwrap_or_else(|_| "100".to_owned())
+                .parse()?,
+            max_retries: env::var("DRAINER_MAX_RETRIES")
+                .unwrap_or_else(|_| "5".to_owned())
+                .parse()?,
+            retry_after_ms: env::var("DRAINER_RETRY_AFTER_MS")
+                .unwrap_or_else(|_| "5000".to_owned())
+                .parse()?,
+        })
+    }
+
+    /// Entries given up on, with the error that stopped them.
+    pub fn dead_letter_stream(&self) -> String {
+        format!("{}_dead_letter", self.stream)
+    }
+}
+
+/// `STORE_BACKEND=hybrid`: redis serves every request and appends its writes to the drainer
+/// stream, while a drainer task in this process replays them into `DRAINER_TARGET`
+/// (default `cassandra`). The target schema is migrated separately with `store migrate up`.
+pub async fn hybrid() -> Result<RedisClient, Box<dyn std::error::Error>> {
+    let config = DrainerConfig::from_env()?;
+    let target = env::var("DRAINER_TARGET").unwrap_or_else(|_| "cassandra".to_owned());
+    if target == "hybrid" {
+        return Err("DRAINER_TARGET cannot be hybrid".into());
+    }
+    let target = Box::pin(create_backend(&target)).await?;
+
+    let mut redis = RedisClient::new().await?;
+    redis.drainer_stream = Some(config.stream.clone());
+
+    // XREADGROUP blocks its connection, so the drainer gets its own instead of a pooled one
+    let client = redis.pool.next().clone_new();
+    client.connect();
+    client.wait_for_connect().await?;
+    let drainer = Drainer {
+        client,
+        config,
+        target,
+    };
+    drainer.create_group().await?;
+    tokio::spawn(drainer.run());
+    Ok(redis)
+}
+
+/// Consumes the drainer stream in a consumer group and replays each entry on `target`.
+///
+/// Entries are acked once applied. Failures stay pending and are claimed again after
+/// `retry_after_ms`, which also picks up entries left by a consumer that died; permanent
+/// failures and entries delivered more than `max_retries` times go to the dead letter stream.
+pub struct Drainer {
+    pub client: fred::clients::RedisClient,
+    pub config: DrainerConfig,
+    pub target: Box<dyn StorageInterface>,
+}
+
+impl Drainer {
+    async fn create_group(&self) -> Result<(), StoreError> {
+        match self
+            .client
+            .xgroup_create::<(), _, _, _>(
+                self.config.stream.as_str(),
+                self.config.group.as_str(),
+                "0",
+                true,
+            )
+            .await
+        {
+            Err(err) if err.details().starts_with("BUSYGROUP") => Ok(()),
+            result => Ok(result?),
+        }
+    }
+
+    pub async fn run(self) {
+        loop {
+            if let Err(err) = self.drain_once().await {
+                eprintln!("drainer {}: {}", self.config.consumer, err);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+
+    /// Retries due pending entries, then waits up to a second for new ones.
+    async fn drain_once(&self) -> Result<(), StoreError> {
+        for (id, fields) in self.claim_pending().await? {
+            self.process(id, fields).await?;
+        }
+
+        let streams = self
+            .client
+            .xreadgroup_map::<String, String, String, String, _, _, _, _>(
+                self.config.group.as_str(),
+                self.config.consumer.as_str(),
+                Some(self.config.batch_size),
+                Some(1000),
+                false,
+                self.config.stream.as_str(),
+                ">",
+            )
+            .await?;
+        for (id, fields) in streams.into_values().flatten() {
+            self.process(id, fields).await?;
+        }
+        Ok(())
+    }
+
+    /// Claims entries pending for longer than `retry_after_ms`, dead lettering the ones that
+    /// ran out of retries.
+    async fn claim_pending(&self) -> Result<Vec<(String, HashMap<String, String>)>, StoreError> {
+        let pending = self
+            .client
+            .xpending::<Vec<(String, String, u64, u64)>, _, _, _>(
+                self.config.stream.as_str(),
+                self.config.group.as_str(),
+                (
+                    self.config.retry_after_ms,
+                    "-",
+                    "+",
+                    self.config.batch_size,
+                ),
+            )
+            .await?;
+
+        let mut retry = Vec::new();
+        for (id, _consumer, _idle, deliveries) in pending {
+            if deliveries > self.config.max_retries {
+                let fields = self
+                    .client
+                    .xrange_values::<String, String, String, _, _, _>(
+                        self.config.stream.as_str(),
+                        id.as_str(),
+                        id.as_str(),
+                        Some(1),
+                    )
+                    .await?
+                    .pop()
+                    .map(|(_, fields)| fields)
+                    .unwrap_or_default();
+                self.dead_letter(&id, fields, "retries exhausted").await?;
+            } else {
+                retry.push(id);
+            }
+        }
+        if retry.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        Ok(self
+            .client
+            .xclaim_values::<String, String, String, _, _, _, _>(
+                self.config.stream.as_str(),
+                self.config.group.as_str(),
+                self.config.consumer.as_str(),
+                self.config.retry_after_ms,
+                retry,
+                None,
+                None,
+                None,
+                false,
+                false,
+            )
+            .await?)
+    }
+
+    async fn process(&self, id: String, fields: HashMap<String, String>) -> Result<(), StoreError> {
+        let mutation = match fields
+            .get("mutation")
+            .map(|mutation| serde_json::from_str::<Mutation>(mutation))
+        {
+            Some(Ok(mutation)) => mutation,
+            Some(Err(err)) => return self.dead_letter(&id, fields, &err.to_string()).await,
+            None => return self.dead_letter(&id, fields, "mutation field missing").await,
+        };
+
+        let op = mutation.op();
+        match self.apply(mutation).await {
+            Ok(()) => {
+                self.client
+                    .xack::<(), _, _, _>(
+                        self.config.stream.as_str(),
+                        self.config.group.as_str(),
+                        id.as_str(),
+                    )
+                    .await?;
+                record(op, "applied");
+                Ok(())
+            }
+            Err(err) if is_retryable(&err) => {
+                // left pending, claim_pending picks it up again after retry_after_ms
+                eprintln!("drainer retrying {} {}: {}", op, id, err);
+                record(op, "retried");
+                Ok(())
+            }
+            Err(err) => self.dead_letter(&id, fields, &err.to_string()).await,
+        }
+    }
+
+    /// Replays `mutation`. A create that already exists or an update the target is already
+    /// past was applied by an earlier delivery, so both count as done.
+    async fn apply(&self, mutation: Mutation) -> Result<(), StoreError> {
+        match mutation {
+            Mutation::CreateIntent { payment_intent } => {
+                match self.target.create_intent(payment_intent).await {
+                    Err(StoreError::AlreadyExists(_)) => Ok(()),
+                    result => result,
+                }
+            }
+            Mutation::CreateAttempt { payment_attempt } => {
+                match self.target.create_attempt(payment_attempt).await {
+                    Err(StoreError::AlreadyExists(_)) => Ok(()),
+                    result => result,
+                }
+            }
+            Mutation::UpdateIntent {
+                merchant_id,
+                payment_id,
+                expected_version,
+                expected_status,
+                update,
+            } => {
+                let result = match expected_status {
+                    Some(status) => {
+                        self.target
+                            .update_intent_if_status(
+                                &merchant_id,
+                                &payment_id,
+                                expected_version,
+                                status,
+                                update,
+                            )
+                            .await
+                    }
+                    None => {
+                        self.target
+                            .update_intent(&merchant_id, &payment_id, expected_version, update)
+                            .await
+                    }
+                };
+                match result {
+                    Err(StoreError::Conflict(_) | StoreError::InvalidTransition(_))
+                        if self
+                            .target
+                            .retrieve_intent(&merchant_id, &payment_id)
+                            .await?
+                            .version
+                            > expected_version =>
+                    {
+                        Ok(())
+                    }
+                    result => result,
+                }
+            }
+            Mutation::UpdateAttempt {
+                merchant_id,
+                payment_id,
+                attempt_id,
+                expected_version,
+                expected_status,
+                update,
+            } => {
+                let result = match expected_status {
+                    Some(status) => {
+                        self.target
+                            .update_attempt_if_status(
+                                &merchant_id,
+                                &payment_id,
+                                &attempt_id,
+                                expected_version,
+                                status,
+                                update,
+                            )
+                            .await
+                    }
+                    None => {
+                        self.target
+                            .update_attempt(
+                                &merchant_id,
+                                &payment_id,
+                                &attempt_id,
+                                expected_version,
+                                update,
+                            )
+                            .await
+                    }
+                };
+                match result {
+                    Err(StoreError::Conflict(_) | StoreError::InvalidTransition(_))
+                        if self
+                            .target
+                            .retrieve_all(&merchant_id, &payment_id)
+                            .await?
+                            .iter()
+                            .any(|payment_attempt| {
+                                payment_attempt.attempt_id == attempt_id
+                                    && payment_attempt.version > expected_version
+                            }) =>
+                    {
+                        Ok(())
+                    }
+                    result => result,
+                }
+            }
+        }
+    }
+
+    async fn dead_letter(
+        &self,
+        id: &str,
+        mut fields: HashMap<String, String>,
+        error: &str,
+    ) -> Result<(), StoreError> {
+        let op = fields
+            .get("mutation")
+            .and_then(|mutation| serde_json::from_str::<Mutation>(mutation).ok())
+            .map_or("unknown", |mutation| mutation.op());
+        eprintln!("drainer dead lettering {} {}: {}", op, id, error);
+        fields.insert("id".to_owned(), id.to_owned());
+        fields.insert("error".to_owned(), error.to_owned());
+        self.client
+            .xadd::<(), _, _, _, _>(
+                self.config.dead_letter_stream(),
+                false,
+                None,
+                "*",
+                fields.into_iter().collect::<Vec<_>>(),
+            )
+            .await?;
+        self.client
+            .xack::<(), _, _, _>(
+                self.config.stream.as_str(),
+                self.config.group.as_str(),
+                id,
+            )
+            .await?;
+        record(op, "dead_lettered");
+        Ok(())
+    }
+}
+
+/// Errors that may clear up on a later delivery: the target being unreachable, or an entry
+/// replayed ahead of the writes it depends on.
+fn is_retryable(err: &StoreError) -> bool {
+    matches!(
+        err,
+        StoreError::Timeout(_)
+            | StoreError::Unavailable(_)
+            | StoreError::Backend(_)
+            | StoreError::Conflict(_)
+            | StoreError::NotFound(_)
+    )
+}
+
+fn record(op: &'static str, outcome: &'static str) {
+    metrics::counter!("drainer_entries", &[("op", op), ("outcome", outcome)]).increment(1);
+}
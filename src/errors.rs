@@ -0,0 +1,126 @@
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+
+/// Errors surfaced by the storage backends, independent of the store behind them.
+#[derive(Debug)]
+pub enum StoreError {
+    NotFound(String),
+    AlreadyExists(String),
+    Conflict(String),
+    /// A status change the transition table does not allow.
+    InvalidTransition(String),
+    Timeout(String),
+    Unavailable(String),
+    Serialization(String),
+    InvalidRequest(String),
+    Backend(String),
+}
+
+impl StoreError {
+    pub fn not_found() -> Self {
+        Self::NotFound("No rows found".to_owned())
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::NotFound(_) => "not_found",
+            Self::AlreadyExists(_) => "already_exists",
+            Self::Conflict(_) => "conflict",
+            Self::InvalidTransition(_) => "invalid_transition",
+            Self::Timeout(_) => "timeout",
+            Self::Unavailable(_) => "unavailable",
+            Self::Serialization(_) => "serialization",
+            Self::InvalidRequest(_) => "invalid_request",
+            Self::Backend(_) => "backend",
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            Self::NotFound(message)
+            | Self::AlreadyExists(message)
+            | Self::Conflict(message)
+            | Self::InvalidTransition(message)
+            | Self::Timeout(message)
+            | Self::Unavailable(message)
+            | Self::Serialization(message)
+            | Self::InvalidRequest(message)
+            | Self::Backend(message) => message,
+        }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::AlreadyExists(_) | Self::Conflict(_) => StatusCode::CONFLICT,
+            Self::InvalidTransition(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            Self::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            Self::Serialization(_) | Self::Backend(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl std::fmt::Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.kind(), self.message())
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl IntoResponse for StoreError {
+    fn into_response(self) -> Response {
+        let body = serde_json::json!({
+            "error": self.kind(),
+            "message": self.message(),
+        });
+        (self.status_code(), axum::Json(body)).into_response()
+    }
+}
+
+impl From<serde_json::Error> for StoreError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Serialization(err.to_string())
+    }
+}
+
+impl From<fred::error::RedisError> for StoreError {
+    fn from(err: fred::error::RedisError) -> Self {
+        use fred::error::RedisErrorKind;
+        match err.kind() {
+            RedisErrorKind::Timeout => Self::Timeout(err.to_string()),
+            RedisErrorKind::IO | RedisErrorKind::Canceled | RedisErrorKind::Cluster => {
+                Self::Unavailable(err.to_string())
+            }
+            RedisErrorKind::NotFound => Self::NotFound(err.to_string()),
+            RedisErrorKind::Parse => Self::Serialization(err.to_string()),
+            _ => Self::Backend(err.to_string()),
+        }
+    }
+}
+
+#[cfg(feature = "cassandra")]
+impl From<cassandra_cpp::Error> for StoreError {
+    fn from(err: cassandra_cpp::Error) -> Self {
+        use cassandra_cpp::{CassErrorCode, ErrorKind};
+        let code = match err.kind() {
+            ErrorKind::CassError(code, _) | ErrorKind::CassErrorResult(code, ..) => Some(*code),
+            _ => None,
+        };
+        match code {
+            Some(
+                CassErrorCode::SERVER_WRITE_TIMEOUT
+                | CassErrorCode::SERVER_READ_TIMEOUT
+                | CassErrorCode::LIB_REQUEST_TIMED_OUT,
+            ) => Self::Timeout(err.to_string()),
+            Some(
+                CassErrorCode::SERVER_UNAVAILABLE
+                | CassErrorCode::SERVER_OVERLOADED
+                | CassErrorCode::LIB_NO_HOSTS_AVAILABLE,
+            ) => Self::Unavailable(err.to_string()),
+            _ => Self::Backend(err.to_string()),
+        }
+    }
+}
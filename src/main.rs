@@ -1,15 +1,29 @@
 use std::env;
 use anyhow::{Context, Result};
+mod cache;
+mod cli;
+#[cfg(feature = "redis")]
+mod drainer;
+#[cfg(feature = "cassandra")]
+mod cql;
+mod errors;
+#[cfg(feature = "cassandra")]
+mod migrations;
 mod store;
 mod models;
+mod shadow;
+mod transfer;
 mod types;
 mod utils;
 mod time;
 
-use axum::{response::IntoResponse, routing::get};
+use axum::{response::IntoResponse, routing::{get, patch, post}, Json};
 use tokio::net::TcpListener;
-use crate::store::App;
-use axum::extract::{State, Path};
+use crate::errors::StoreError;
+use crate::store::{App, RequestConsistency};
+use crate::types::{get_large_value, IntentStatus, PaymentAttempt, PaymentAttemptUpdate, PaymentIntent, PaymentIntentUpdate};
+use axum::extract::{FromRequestParts, State, Path};
+use axum::http::request::Parts;
 use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle, Matcher};
 
 fn metrics_app() -> axum::Router {
@@ -42,22 +56,31 @@ async fn start_metrics_server() {
 }
 
 
-async fn start_app(){
-    let store = App::create_state().await.expect("state creation failed");
-
-    let server_host = env::var("SERVER_HOST").unwrap_or("localhost".to_string());
-    let server_port = env::var("SERVER_PORT").unwrap_or("8000".to_string());
-
-    let router = axum::Router::new()
+fn app_router(store: App) -> axum::Router {
+    axum::Router::new()
         .route("/init_db", get(init_db))
         .route("/create/:payment_id", get(create_payment)) // create payment intent
         .route("/pay/:payment_id/:version", get(pay))// create payment attempt
+        .route("/payment_intents", post(create_intent))
+        .route("/payment_attempts", post(create_attempt))
+        .route("/payment_intents/:payment_id", patch(patch_intent))
+        .route("/payment_attempts/:payment_id/:attempt_id", patch(patch_attempt))
         .route("/update_intent/:payment_intent_id", get(update_intent))
         .route("/update_attempt/pay/:version/:payment_attempt_id", get(update_attempt))
         .route("/retrieve/payment_attempt/:payment_id", get(retrieve_attempt))
         .route("/retrieve/payment_intent/:payment_id", get(retrieve))
+        .layer(axum::middleware::from_fn(request_consistency))
         .with_state(store)
-        .route("/health", get(|| async { "OK"}));
+        .route("/health", get(|| async { "OK"}))
+}
+
+async fn start_app(){
+    let store = App::create_state().await.expect("state creation failed");
+
+    let server_host = env::var("SERVER_HOST").unwrap_or("localhost".to_string());
+    let server_port = env::var("SERVER_PORT").unwrap_or("8000".to_string());
+
+    let router = app_router(store);
     axum::serve(
         TcpListener::bind((server_host
                                 , server_port.parse::<u16>().context("invalid server port").expect("invalid server port"))).await.expect("port binding failed"),
@@ -65,41 +88,266 @@ async fn start_app(){
 }
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if !args.is_empty() {
+        return cli::run(&args).await;
+    }
     let (_, _) = tokio::join!(start_metrics_server(), start_app());
     Ok(())
 
 }
 
-async fn init_db(State(app) : State<App>) -> Result<impl IntoResponse, String>{
-    let _ = app.db.prepare().await.map_err(|_| "init failed")?;
-    Ok(axum::Json(()))
+async fn init_db(State(app) : State<App>) -> Result<impl IntoResponse, StoreError>{
+    app.db.prepare().await?;
+    Ok(axum::Json(app.db.schema_status().await?))
 }
-async fn create_payment(State(app) : State<App> , Path(payment_id): Path<String>) -> Result<impl IntoResponse , String>{
-    let _ = app.db.create_intent(payment_id).await.map_err(|e| e.to_string())?;
+async fn create_payment(State(app) : State<App> , MerchantId(merchant_id): MerchantId, Path(payment_id): Path<String>) -> Result<impl IntoResponse, StoreError>{
+    app.db.create_intent(PaymentIntent::new(payment_id, merchant_id)).await?;
     Ok(axum::Json(()))
 }
 
-async fn pay(State(app) : State<App> ,Path((payment_id,version)): Path<(String,String)>) -> Result<impl IntoResponse , String>{
-    let _ = app.db.retrieve_intent(payment_id.as_ref()).await.map_err(|e| e.to_string())?;
-    let _ = app.db.create_attempt(payment_id, version).await.map_err(|e| e.to_string())?;
+async fn create_intent(State(app) : State<App>, MerchantId(merchant_id): MerchantId, Json(mut payment_intent): Json<PaymentIntent>) -> Result<impl IntoResponse, StoreError>{
+    payment_intent.merchant_id = merchant_id;
+    payment_intent.validate().map_err(StoreError::InvalidRequest)?;
+    let now = utils::now();
+    payment_intent.created_at = now;
+    payment_intent.modified_at = now;
+    payment_intent.version = 1;
+    app.db.create_intent(payment_intent.clone()).await?;
+    Ok(Json(payment_intent))
+}
+
+async fn create_attempt(State(app) : State<App>, MerchantId(merchant_id): MerchantId, Json(mut payment_attempt): Json<PaymentAttempt>) -> Result<impl IntoResponse, StoreError>{
+    payment_attempt.merchant_id = merchant_id;
+    payment_attempt.validate().map_err(StoreError::InvalidRequest)?;
+    let now = utils::now();
+    payment_attempt.created_at = now;
+    payment_attempt.modified_at = now;
+    payment_attempt.version = 1;
+    app.db.create_attempt(payment_attempt.clone()).await?;
+    Ok(Json(payment_attempt))
+}
+
+async fn patch_intent(State(app) : State<App>, MerchantId(merchant_id): MerchantId, ExpectedVersion(expected_version): ExpectedVersion, expected_status: Option<ExpectedStatus>, Path(payment_id): Path<String>, Json(mut payment_intent_update): Json<PaymentIntentUpdate>) -> Result<impl IntoResponse, StoreError>{
+    if payment_intent_update.is_empty() {
+        return Err(StoreError::InvalidRequest("No fields to update".to_owned()));
+    }
+    payment_intent_update.modified_at = Some(utils::now());
+    match expected_status {
+        Some(expected_status) => app.db.update_intent_if_status(merchant_id.as_ref(), payment_id.as_ref(), expected_version, expected_status.parse()?, payment_intent_update).await?,
+        None => app.db.update_intent(merchant_id.as_ref(), payment_id.as_ref(), expected_version, payment_intent_update).await?,
+    }
+    let payment_intent = app.db.retrieve_intent(merchant_id.as_ref(), payment_id.as_ref()).await?;
+    Ok(Json(payment_intent))
+}
+
+async fn patch_attempt(State(app) : State<App>, MerchantId(merchant_id): MerchantId, ExpectedVersion(expected_version): ExpectedVersion, expected_status: Option<ExpectedStatus>, Path((payment_id, attempt_id)): Path<(String, String)>, Json(mut payment_attempt_update): Json<PaymentAttemptUpdate>) -> Result<impl IntoResponse, StoreError>{
+    if payment_attempt_update.is_empty() {
+        return Err(StoreError::InvalidRequest("No fields to update".to_owned()));
+    }
+    payment_attempt_update.modified_at = Some(utils::now());
+    match expected_status {
+        Some(expected_status) => app.db.update_attempt_if_status(merchant_id.as_ref(), payment_id.as_ref(), attempt_id.as_ref(), expected_version, expected_status.parse()?, payment_attempt_update).await?,
+        None => app.db.update_attempt(merchant_id.as_ref(), payment_id.as_ref(), attempt_id.as_ref(), expected_version, payment_attempt_update).await?,
+    }
+    let payment_attempt = app.db.retrieve_all(merchant_id.as_ref(), payment_id.as_ref()).await?
+        .into_iter()
+        .find(|payment_attempt| payment_attempt.attempt_id == attempt_id)
+        .ok_or_else(StoreError::not_found)?;
+    Ok(Json(payment_attempt))
+}
+
+async fn pay(State(app) : State<App> , MerchantId(merchant_id): MerchantId, Path((payment_id,version)): Path<(String,String)>) -> Result<impl IntoResponse, StoreError>{
+    app.db.create_attempt(PaymentAttempt::new(payment_id, merchant_id, version)).await?;
     Ok(axum::Json(()))
 }
-async fn update_attempt(State(app) : State<App> , Path((version, payment_attempt_id)): Path<(String,String)>) -> Result<impl IntoResponse , String>{
-    let _ = app.db.update_attempt(payment_attempt_id.as_ref(), version).await.map_err(|e| e.to_string())?;
+async fn update_attempt(State(app) : State<App> , MerchantId(merchant_id): MerchantId, Path((version, payment_attempt_id)): Path<(String,String)>) -> Result<impl IntoResponse, StoreError>{
+    let payment_attempt_update = PaymentAttemptUpdate {
+        connector_metadata: Some(get_large_value()),
+        modified_at: Some(utils::now()),
+        ..Default::default()
+    };
+    let attempt_id = PaymentAttempt::attempt_id_for(&payment_attempt_id, &version);
+    let payment_attempt = app.db.retrieve_all(merchant_id.as_ref(), payment_attempt_id.as_ref()).await?
+        .into_iter()
+        .find(|payment_attempt| payment_attempt.attempt_id == attempt_id)
+        .ok_or_else(StoreError::not_found)?;
+    app.db.update_attempt(merchant_id.as_ref(), payment_attempt_id.as_ref(), attempt_id.as_ref(), payment_attempt.version, payment_attempt_update).await?;
     Ok(axum::Json(()))
 }
 
-async fn update_intent(State(app) : State<App> , Path(payment_intent_id): Path<String>) -> Result<impl IntoResponse , String>{
-    let _ = app.db.update_intent(payment_intent_id.as_ref()).await.map_err(|e| e.to_string())?;
+async fn update_intent(State(app) : State<App> , MerchantId(merchant_id): MerchantId, Path(payment_intent_id): Path<String>) -> Result<impl IntoResponse, StoreError>{
+    let payment_intent_update = PaymentIntentUpdate {
+        status: Some(IntentStatus::Succeeded),
+        modified_at: Some(utils::now()),
+        ..Default::default()
+    };
+    let payment_intent = app.db.retrieve_intent(merchant_id.as_ref(), payment_intent_id.as_ref()).await?;
+    app.db.update_intent(merchant_id.as_ref(), payment_intent_id.as_ref(), payment_intent.version, payment_intent_update).await?;
     Ok(axum::Json(()))
 }
-async fn retrieve_attempt(State(app) : State<App> , Path(payment_id): Path<String>) -> Result<impl IntoResponse , String>{
-    let _ = app.db.retrieve_all(payment_id.as_ref()).await.map_err(|e| e.to_string())?;
-    Ok(axum::Json(()))
+async fn retrieve_attempt(State(app) : State<App> , MerchantId(merchant_id): MerchantId, Path(payment_id): Path<String>) -> Result<impl IntoResponse, StoreError>{
+    let payment_attempts = app.db.retrieve_all(merchant_id.as_ref(), payment_id.as_ref()).await?;
+    Ok(axum::Json(payment_attempts))
 }
 
-async fn retrieve(State(app): State<App>, Path(payment_id) : Path<String>) -> Result<impl IntoResponse, String>
+async fn retrieve(State(app): State<App>, MerchantId(merchant_id): MerchantId, Path(payment_id) : Path<String>) -> Result<impl IntoResponse, StoreError>
 {
-    let _ = app.db.retrieve_intent(payment_id.as_ref()).await.map_err(|e| e.to_string())?;
-    Ok(axum::Json(()))
-}
\ No newline at end of file
+    let payment_intent = app.db.retrieve_intent(merchant_id.as_ref(), payment_id.as_ref()).await?;
+    Ok(axum::Json(payment_intent))
+}
+
+/// Scopes the consistency override headers around the handler so the cassandra backend can pick them up.
+async fn request_consistency(request: axum::extract::Request, next: axum::middleware::Next) -> axum::response::Response {
+    let overrides = RequestConsistency {
+        consistency: header_value(request.headers(), "x-cassandra-consistency"),
+        serial_consistency: header_value(request.headers(), "x-cassandra-serial-consistency"),
+    };
+    store::REQUEST_CONSISTENCY.scope(overrides, next.run(request)).await
+}
+
+fn header_value(headers: &axum::http::HeaderMap, name: &str) -> Option<String> {
+    headers.get(name).and_then(|value| value.to_str().ok()).map(str::to_owned)
+}
+
+/// Merchant the request is made for, taken from the `x-merchant-id` header.
+struct MerchantId(String);
+
+#[axum::async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for MerchantId {
+    type Rejection = StoreError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .headers
+            .get("x-merchant-id")
+            .and_then(|value| value.to_str().ok())
+            .filter(|value| !value.is_empty())
+            .map(|value| MerchantId(value.to_owned()))
+            .ok_or_else(|| StoreError::InvalidRequest("x-merchant-id header missing".to_owned()))
+    }
+}
+
+/// Status a patch requires the record to still be in, from the optional `x-expected-status` header.
+/// When present the update is applied as a compare-and-set.
+struct ExpectedStatus(String);
+
+impl ExpectedStatus {
+    /// The header as the model's status enum, by variant name.
+    fn parse<T: serde::de::DeserializeOwned>(self) -> Result<T, StoreError> {
+        serde_json::from_value(serde_json::Value::String(self.0))
+            .map_err(|_| StoreError::InvalidRequest("unknown x-expected-status".to_owned()))
+    }
+}
+
+#[axum::async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ExpectedStatus {
+    type Rejection = StoreError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        header_value(&parts.headers, "x-expected-status")
+            .map(ExpectedStatus)
+            .ok_or_else(|| StoreError::InvalidRequest("x-expected-status header missing".to_owned()))
+    }
+}
+
+/// Version a patch was based on, from the required `x-expected-version` header.
+struct ExpectedVersion(i64);
+
+#[axum::async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ExpectedVersion {
+    type Rejection = StoreError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        header_value(&parts.headers, "x-expected-version")
+            .and_then(|value| value.parse().ok())
+            .map(ExpectedVersion)
+            .ok_or_else(|| StoreError::InvalidRequest("x-expected-version header missing or invalid".to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::InMemoryStore;
+    use axum::body::Body;
+    use axum::http::{Method, Request, StatusCode};
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    fn router() -> axum::Router {
+        app_router(App { db: Box::new(InMemoryStore::new()) })
+    }
+
+    fn request(method: Method, uri: &str, headers: &[(&str, &str)], body: Body) -> Request<Body> {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json");
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        request.body(body).unwrap()
+    }
+
+    async fn send(router: &axum::Router, request: Request<Body>) -> (StatusCode, serde_json::Value) {
+        let response = router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null))
+    }
+
+    async fn create(router: &axum::Router, payment_id: &str) -> StatusCode {
+        let uri = format!("/create/{}", payment_id);
+        send(router, request(Method::GET, &uri, &[("x-merchant-id", "m1")], Body::empty())).await.0
+    }
+
+    #[tokio::test]
+    async fn create_and_retrieve_intent() {
+        let router = router();
+        assert_eq!(create(&router, "p1").await, StatusCode::OK);
+
+        let (status, body) = send(&router, request(Method::GET, "/retrieve/payment_intent/p1", &[("x-merchant-id", "m1")], Body::empty())).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["payment_id"], "p1");
+        assert_eq!(body["merchant_id"], "m1");
+        assert_eq!(body["version"], 1);
+    }
+
+    #[tokio::test]
+    async fn duplicate_create_conflicts() {
+        let router = router();
+        assert_eq!(create(&router, "p1").await, StatusCode::OK);
+        assert_eq!(create(&router, "p1").await, StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn patch_with_stale_version_conflicts() {
+        let router = router();
+        assert_eq!(create(&router, "p1").await, StatusCode::OK);
+        let patch = |version: &'static str| {
+            request(
+                Method::PATCH,
+                "/payment_intents/p1",
+                &[("x-merchant-id", "m1"), ("x-expected-version", version)],
+                Body::from(r#"{"description": "updated"}"#),
+            )
+        };
+
+        let (status, body) = send(&router, patch("1")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["version"], 2);
+
+        let (status, body) = send(&router, patch("1")).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["error"], "conflict");
+    }
+
+    #[tokio::test]
+    async fn missing_merchant_id_is_rejected() {
+        let router = router();
+        let (status, body) = send(&router, request(Method::GET, "/create/p1", &[], Body::empty())).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "invalid_request");
+    }
+}
@@ -0,0 +1,129 @@
+use crate::cql::{CqlColumn, Keyspace};
+use crate::errors::StoreError;
+use crate::store::MigrationStatus;
+use cassandra_cpp::{BindRustType, LendingIterator, Session};
+use std::collections::BTreeMap;
+use time::PrimitiveDateTime;
+
+/// A numbered CQL file under `src/migrations`, applied once in version order.
+pub struct Migration {
+    pub version: i32,
+    pub name: &'static str,
+    pub cql: &'static str,
+}
+
+/// Every migration in the order it is applied. New files get the next number and are
+/// appended here; applied files must not be edited.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "create_keyspace",
+        cql: include_str!("migrations/0001_create_keyspace.cql"),
+    },
+    Migration {
+        version: 2,
+        name: "create_payment_tables",
+        cql: include_str!("migrations/0002_create_payment_tables.cql"),
+    },
+    Migration {
+        version: 3,
+        name: "add_record_version",
+        cql: include_str!("migrations/0003_add_record_version.cql"),
+    },
+    Migration {
+        version: 4,
+        name: "add_missing_model_columns",
+        cql: include_str!("migrations/0004_add_missing_model_columns.cql"),
+    },
+];
+
+impl Migration {
+    /// The driver runs one statement per request, so files are split on `;`.
+    /// `{keyspace}` and `{replication}` are filled in from the configured keyspace.
+    fn statements(&self, keyspace: &Keyspace) -> Vec<String> {
+        self.cql
+            .split(';')
+            .map(str::trim)
+            .filter(|statement| !statement.is_empty())
+            .map(|statement| {
+                statement
+                    .replace("{keyspace}", &keyspace.name)
+                    .replace("{replication}", &keyspace.replication)
+            })
+            .collect()
+    }
+}
+
+/// Versions recorded in `schema_migrations`, empty before the first migration created it.
+async fn applied(
+    session: &Session,
+    keyspace: &Keyspace,
+) -> Result<BTreeMap<i32, PrimitiveDateTime>, StoreError> {
+    let query = format!(
+        "SELECT version, applied_at FROM {}.schema_migrations;",
+        keyspace.name
+    );
+    let rows = match session.execute(query.as_str()).await {
+        Ok(rows) => rows,
+        Err(err) if is_missing_schema(&err) => return Ok(BTreeMap::new()),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut applied = BTreeMap::new();
+    let mut rows = rows.iter();
+    while let Some(row) = rows.next() {
+        applied.insert(
+            i32::from_column(&row, "version")?,
+            PrimitiveDateTime::from_column(&row, "applied_at")?,
+        );
+    }
+    Ok(applied)
+}
+
+fn is_missing_schema(err: &cassandra_cpp::Error) -> bool {
+    let message = err.to_string();
+    message.contains("unconfigured table") || message.contains("does not exist")
+}
+
+/// Applies pending migrations in order. Every statement is written to be re-runnable, so a
+/// migration interrupted before it was recorded is simply applied again.
+pub async fn migrate(session: &Session, keyspace: &Keyspace) -> Result<(), StoreError> {
+    let applied = applied(session, keyspace).await?;
+    for migration in MIGRATIONS
+        .iter()
+        .filter(|migration| !applied.contains_key(&migration.version))
+    {
+        for statement in migration.statements(keyspace) {
+            session.execute(statement.as_str()).await?;
+        }
+
+        let mut record = session.statement(format!(
+            "INSERT INTO {}.schema_migrations (version, name, applied_at) VALUES (?, ?, ?);",
+            keyspace.name
+        ));
+        record.bind(0, migration.version)?;
+        record.bind(1, migration.name)?;
+        crate::utils::now().bind(&mut record, 2)?;
+        record.execute().await?;
+        println!(
+            "applied migration {:04}_{}",
+            migration.version, migration.name
+        );
+    }
+    Ok(())
+}
+
+pub async fn status(
+    session: &Session,
+    keyspace: &Keyspace,
+) -> Result<Vec<MigrationStatus>, StoreError> {
+    let applied = applied(session, keyspace).await?;
+    Ok(MIGRATIONS
+        .iter()
+        .map(|migration| MigrationStatus {
+            version: migration.version,
+            name: migration.name.to_owned(),
+            applied_at: applied.get(&migration.version).copied(),
+        })
+        .collect())
+}
@@ -1,333 +1,1492 @@
-use crate::store::RedisClient;
+use crate::errors::StoreError;
+use crate::store::{InMemoryStore, RedisClient};
 use crate::types::*;
-use anyhow::Context;
+use dashmap::mapref::entry::Entry;
+use std::collections::btree_map;
 
+#[cfg(feature = "cassandra")]
+use crate::cql::CqlModel;
 #[cfg(feature = "cassandra")]
 use crate::store::CassClient;
 
 #[cfg(feature = "cassandra")]
-use cassandra_cpp::{BindRustType, LendingIterator};
-use fred::prelude::{HashesInterface, ServerInterface};
+use cassandra_cpp::{BatchType, BindRustType, LendingIterator};
+use fred::bytes::Bytes;
+use fred::prelude::HashesInterface;
+use fred::types::RedisValue;
+use fred::types::Scanner;
 use futures::StreamExt;
 #[async_trait::async_trait]
 pub trait PaymentIntentInterface {
-    async fn create_intent(&self, payment_id: String) -> Result<(), Box<dyn std::error::Error>>;
+    async fn create_intent(&self, payment_intent: PaymentIntent) -> Result<(), StoreError>;
     async fn retrieve_intent<'a>(
         &self,
+        merchant_id: &'a str,
         payment_id: &'a str,
-    ) -> Result<(), Box<dyn std::error::Error>>;
+    ) -> Result<PaymentIntent, StoreError>;
+    /// Applies the update while the intent is still at `expected_version` and bumps its version,
+    /// failing with `StoreError::Conflict` when it was updated in between and with
+    /// `StoreError::InvalidTransition` when the status change is not allowed.
     async fn update_intent<'a>(
         &self,
+        merchant_id: &'a str,
         payment_id: &'a str,
-    ) -> Result<(), Box<dyn std::error::Error>>;
+        expected_version: i64,
+        payment_intent_update: PaymentIntentUpdate,
+    ) -> Result<(), StoreError>;
+
+    /// `update_intent` that also requires the intent to still be in `expected_status`.
+    /// Optional, backends without compare-and-set refuse it.
+    async fn update_intent_if_status<'a>(
+        &self,
+        _merchant_id: &'a str,
+        _payment_id: &'a str,
+        _expected_version: i64,
+        _expected_status: IntentStatus,
+        _payment_intent_update: PaymentIntentUpdate,
+    ) -> Result<(), StoreError> {
+        Err(cas_unsupported())
+    }
 }
 
 #[async_trait::async_trait]
 pub trait PaymentAttemptInterface {
-    async fn create_attempt(
+    /// Inserts the attempt and makes it the intent's `active_attempt_id`, bumping
+    /// `attempt_count` and the intent version. Fails with `StoreError::NotFound` when the
+    /// intent does not exist.
+    ///
+    /// Redis and the in-memory store do both in one atomic write. Cassandra cannot hold a
+    /// condition across two tables, so it inserts the attempt first and then links it with a
+    /// second conditional write on the intent: readers can see the attempt before the intent
+    /// points at it, and a crash between the two leaves an attempt no intent points at.
+    async fn create_attempt(&self, payment_attempt: PaymentAttempt) -> Result<(), StoreError>;
+    async fn retrieve_all<'a>(
         &self,
-        payment_id: String,
-        version: String,
-    ) -> Result<(), Box<dyn std::error::Error>>;
-    async fn retrieve_all<'a>(&self, payment_id: &'a str)
-        -> Result<(), Box<dyn std::error::Error>>;
+        merchant_id: &'a str,
+        payment_id: &'a str,
+    ) -> Result<Vec<PaymentAttempt>, StoreError>;
+    /// Applies the update while the attempt is still at `expected_version` and bumps its version,
+    /// failing with `StoreError::Conflict` when it was updated in between and with
+    /// `StoreError::InvalidTransition` when the status change is not allowed.
     async fn update_attempt<'a>(
         &self,
+        merchant_id: &'a str,
         payment_id: &'a str,
-        version: String,
-    ) -> Result<(), Box<dyn std::error::Error>>;
+        attempt_id: &'a str,
+        expected_version: i64,
+        payment_attempt_update: PaymentAttemptUpdate,
+    ) -> Result<(), StoreError>;
+
+    /// `update_attempt` that also requires the attempt to still be in `expected_status`.
+    /// Optional, backends without compare-and-set refuse it.
+    async fn update_attempt_if_status<'a>(
+        &self,
+        _merchant_id: &'a str,
+        _payment_id: &'a str,
+        _attempt_id: &'a str,
+        _expected_version: i64,
+        _expected_status: AttemptStatus,
+        _payment_attempt_update: PaymentAttemptUpdate,
+    ) -> Result<(), StoreError> {
+        Err(cas_unsupported())
+    }
 }
 
-fn insert_intent_cql() -> String {
-    "INSERT INTO payments.payment_intents (payment_id, merchant_id, status, amount, currency, amount_captured, customer_id, description, return_url, metadata, connector_id, shipping_address_id, billing_address_id, statement_descriptor_name, statement_descriptor_suffix, created_at, modified_at, last_synced, setup_future_usage, off_session, client_secret, active_attempt_id, business_country, business_label, order_details, allowed_payment_method_types, connector_metadata, feature_metadata, attempt_count, profile_id, merchant_decision, payment_link_id, payment_confirm_source, updated_by, surcharge_applicable, request_incremental_authorization, incremental_authorization_allowed, authorization_count, session_expiry, fingerprint_id, request_external_three_ds_authentication, charges, frm_metadata) VALUES(?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);" 
-        .to_owned()
+#[async_trait::async_trait]
+pub trait PaymentImportInterface {
+    /// Writes a payment copied from another backend as it is, without version checks and
+    /// keeping the intent's attempt count rather than bumping it. Records already present are
+    /// overwritten, so copying the same payment twice leaves one copy.
+    async fn import_payment(
+        &self,
+        payment_intent: PaymentIntent,
+        payment_attempts: Vec<PaymentAttempt>,
+    ) -> Result<(), StoreError>;
 }
-fn insert_attempt_cql() -> String {
-    "INSERT INTO payments.payment_attempts ( payment_id, merchant_id, attempt_id, status, amount, currency, save_to_locker, connector, error_message, offer_amount, surcharge_amount, tax_amount, payment_method_id, payment_method, connector_transaction_id, capture_method, capture_on, confirm, authentication_type, created_at, modified_at, last_synced, cancellation_reason, amount_to_capture, mandate_id, browser_info, error_code, payment_token, connector_metadata, payment_experience, payment_method_type, payment_method_data, business_sub_label, straight_through_algorithm, preprocessing_step_id, mandate_details, error_reason, multiple_capture_count, connector_response_reference_id, amount_capturable, updated_by, merchant_connector_id, authentication_data, encoded_data, unified_code, unified_message, net_amount, external_three_ds_authentication_attempted, authentication_connector, authentication_id, mandate_data, fingerprint_id, payment_method_billing_address_id, charge_id, client_source, client_version ) VALUES ( ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ? );"
-        .to_owned()
+
+fn cas_unsupported() -> StoreError {
+    StoreError::Backend("compare-and-set updates are not supported by this backend".to_owned())
 }
 
-fn select_payment_attempt_all() -> String {
-    "SELECT * FROM payments.payment_attempts WHERE payment_id = ? AND merchant_id = ?;".to_owned()
+/// Whether a lightweight transaction went through, from the `[applied]` column it always
+/// returns first.
+#[cfg(feature = "cassandra")]
+fn lwt_applied(rows: &cassandra_cpp::CassResult) -> Result<bool, StoreError> {
+    let row = rows.first_row().ok_or_else(|| {
+        StoreError::Backend("lightweight transaction returned no rows".to_owned())
+    })?;
+    Ok(row.get_column(0)?.get_bool()?)
 }
 
-fn update_attempt_cql() -> String {
-    "UPDATE payments.payment_attempts set connector_metadata = ? WHERE payment_id = ? AND merchant_id = ? AND attempt_id = ?;"
-      .to_owned()
+/// Text a status is stored and compared as, the variant name for enums.
+fn status_text<T: serde::Serialize>(status: &T) -> Result<String, StoreError> {
+    match serde_json::to_value(status)? {
+        serde_json::Value::String(status) => Ok(status),
+        other => Err(StoreError::Serialization(format!(
+            "{} is not a status",
+            other
+        ))),
+    }
+}
+
+#[cfg(feature = "cassandra")]
+impl CassClient {
+    /// Lightweight transaction setting the patched `columns` and the next version on the row
+    /// addressed by `keys`, applied only while `version` (and `status`) still match.
+    async fn versioned_update<M: CqlModel>(
+        &self,
+        model_name: &str,
+        mut columns: Vec<&'static str>,
+        bind_columns: impl FnOnce(&mut cassandra_cpp::Statement) -> Result<usize, StoreError>,
+        keys: &[&str],
+        expected_version: i64,
+        expected_status: Option<String>,
+    ) -> Result<(), StoreError> {
+        columns.push("version");
+        let mut conditions = vec!["version"];
+        if expected_status.is_some() {
+            conditions.push("status");
+        }
+        let mut statement = self
+            .statement(M::update_if_cql(&self.keyspace.name, &columns, &conditions))
+            .await?;
+        let loc = bind_columns(&mut statement)?;
+        statement.bind(loc, expected_version + 1)?;
+        for (offset, key) in keys.iter().enumerate() {
+            statement.bind(loc + 1 + offset, *key)?;
+        }
+        let loc = loc + 1 + keys.len();
+        if expected_version == 0 {
+            // rows from before the version column hold null, which `IF version = null` matches
+            statement.bind_null(loc)?;
+        } else {
+            statement.bind(loc, expected_version)?;
+        }
+        if let Some(status) = &expected_status {
+            statement.bind(loc + 1, status.as_str())?;
+        }
+        self.set_consistency(&mut statement, model_name, "UPDATE")?;
+
+        let rows = crate::utils::time_wrapper(
+            statement.execute(),
+            &self.latency_label(model_name),
+            "UPDATE",
+        )
+        .await?;
+        if !lwt_applied(&rows)? {
+            return Err(StoreError::Conflict(format!(
+                "{} is missing or changed since version {}",
+                model_name.replace('_', " "),
+                expected_version
+            )));
+        }
+        Ok(())
+    }
 }
 
-fn update_intent_cql() -> String {
-    "UPDATE payments.payment_intents set status = ? WHERE payment_id = ? AND merchant_id = ?;"
-        .to_string()
+/// A write based on a version or status the row has since moved past.
+#[cfg(feature = "cassandra")]
+fn stale(model_name: &str, expected_version: i64) -> StoreError {
+    StoreError::Conflict(format!(
+        "{} changed since version {}",
+        model_name.replace('_', " "),
+        expected_version
+    ))
 }
 
 #[cfg(feature = "cassandra")]
-#[async_trait::async_trait]
-impl PaymentAttemptInterface for CassClient {
-    async fn create_attempt(
+impl CassClient {
+    /// Points the intent at a just inserted attempt with a compare-and-set on its version,
+    /// re-reading it when a concurrent write moved the version first.
+    async fn link_attempt(
         &self,
+        mut payment_intent: PaymentIntent,
+        payment_attempt: &PaymentAttempt,
+    ) -> Result<(), StoreError> {
+        let mut retries = 0;
+        loop {
+            let expected_version = payment_intent.version;
+            payment_intent.record_attempt(&payment_attempt.attempt_id);
+            let result = self
+                .versioned_update::<PaymentIntent>(
+                    "payment_intent",
+                    PaymentIntent::ATTEMPT_COLUMNS.to_vec(),
+                    |statement| {
+                        statement.bind(0, payment_intent.active_attempt_id.as_str())?;
+                        statement.bind(1, payment_intent.attempt_count)?;
+                        Ok(2)
+                    },
+                    &[&payment_attempt.payment_id, &payment_attempt.merchant_id],
+                    expected_version,
+                    None,
+                )
+                .await;
+            match result {
+                Err(StoreError::Conflict(_)) if retries < ATTEMPT_LINK_RETRIES => {
+                    retries += 1;
+                    payment_intent = self
+                        .retrieve_intent(&payment_attempt.merchant_id, &payment_attempt.payment_id)
+                        .await?;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn retrieve_attempt(
+        &self,
+        merchant_id: &str,
+        payment_id: &str,
+        attempt_id: &str,
+    ) -> Result<PaymentAttempt, StoreError> {
+        self.retrieve_all(merchant_id, payment_id)
+            .await?
+            .into_iter()
+            .find(|payment_attempt| payment_attempt.attempt_id == attempt_id)
+            .ok_or_else(StoreError::not_found)
+    }
+
+    /// Deletes an attempt whose intent could not be linked, so a failed create leaves nothing.
+    async fn remove_attempt(&self, payment_attempt: &PaymentAttempt) -> Result<(), StoreError> {
+        let mut statement = self
+            .statement(PaymentAttempt::delete_cql(&self.keyspace.name))
+            .await?;
+        statement.bind(0, payment_attempt.payment_id.as_str())?;
+        statement.bind(1, payment_attempt.merchant_id.as_str())?;
+        statement.bind(2, payment_attempt.attempt_id.as_str())?;
+        self.set_consistency(&mut statement, "payment_attempt", "CREATE")?;
+        crate::utils::time_wrapper(
+            statement.execute(),
+            &self.latency_label("payment_attempt"),
+            "DELETE",
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+/// Times `create_attempt` re-reads an intent that concurrent writes keep moving.
+#[cfg(feature = "cassandra")]
+const ATTEMPT_LINK_RETRIES: usize = 3;
+
+/// A write made on redis, appended to the drainer stream as JSON under the `mutation` field
+/// and replayed as the same call on the target backend.
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Mutation {
+    CreateIntent {
+        payment_intent: PaymentIntent,
+    },
+    UpdateIntent {
+        merchant_id: String,
         payment_id: String,
-        version: String,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let mut statement = self.cassandra_session.statement(insert_attempt_cql());
-        let _ = statement.set_consistency(cassandra_cpp::Consistency::ONE)?;
-        PaymentAttempt::new(payment_id, version).populate_statement(&mut statement)?;
-        let _rows =
-            crate::utils::time_wrapper(statement.execute(), "payment_attempt", "CREATE").await?;
+        expected_version: i64,
+        expected_status: Option<IntentStatus>,
+        update: PaymentIntentUpdate,
+    },
+    CreateAttempt {
+        payment_attempt: PaymentAttempt,
+    },
+    UpdateAttempt {
+        merchant_id: String,
+        payment_id: String,
+        attempt_id: String,
+        expected_version: i64,
+        expected_status: Option<AttemptStatus>,
+        update: PaymentAttemptUpdate,
+    },
+}
+
+impl RedisClient {
+    /// `mutation` serialized for the drainer stream, only built in hybrid mode.
+    fn drainer_entry(
+        &self,
+        mutation: impl FnOnce() -> Mutation,
+    ) -> Result<Option<String>, StoreError> {
+        match self.drainer_stream {
+            Some(_) => Ok(Some(serde_json::to_string(&mutation())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Script keys and arguments with the drainer stream and `mutation` appended in hybrid
+    /// mode, so the script adds the stream entry in the same atomic call as its write.
+    fn script_call(
+        &self,
+        key: &str,
+        mut args: Vec<RedisValue>,
+        mutation: Option<String>,
+    ) -> (Vec<String>, Vec<RedisValue>) {
+        let mut keys = vec![key.to_owned()];
+        if let (Some(stream), Some(mutation)) = (&self.drainer_stream, mutation) {
+            keys.push(stream.clone());
+            args.push(RedisValue::from(mutation));
+        }
+        (keys, args)
+    }
+
+    /// Writes `record` under `field` through the `create_if_absent` script, failing with
+    /// `AlreadyExists` when the field is taken.
+    async fn create_field<T: serde::Serialize>(
+        &self,
+        model_name: &str,
+        key: String,
+        field: String,
+        record: &T,
+        mutation: Option<String>,
+    ) -> Result<(), StoreError> {
+        let (keys, args) = self.script_call(
+            &key,
+            vec![
+                RedisValue::from(field),
+                RedisValue::from(Bytes::from(serde_json::to_vec(record)?)),
+            ],
+            mutation,
+        );
+        let client = self.pool.next();
+        crate::utils::time_wrapper(
+            async {
+                let inserted = self
+                    .scripts
+                    .create_if_absent
+                    .evalsha_with_reload::<i64, _, _>(client, keys, args)
+                    .await?;
+                if inserted != 1 {
+                    return Err(StoreError::AlreadyExists(format!(
+                        "{} already exists",
+                        model_name.trim_start_matches("redis_").replace('_', " ")
+                    )));
+                }
+                self.wait_for_replicas(client, model_name).await
+            },
+            model_name,
+            "INSERT",
+        )
+        .await
+    }
+
+    /// Read-modify-write of one hash field guarded by the record version. `patch` is applied
+    /// locally and the result is written back by the `versioned_set` script only while the stored
+    /// record is still at `expected_version` (and `expected_status`).
+    #[allow(clippy::too_many_arguments)]
+    async fn versioned_update<T>(
+        &self,
+        model_name: &str,
+        key: String,
+        field: String,
+        expected_version: i64,
+        expected_status: Option<String>,
+        patch: impl FnOnce(&mut T) -> Result<(), StoreError>,
+        mutation: Option<String>,
+    ) -> Result<(), StoreError>
+    where
+        T: serde::Serialize + serde::de::DeserializeOwned,
+    {
+        let stale = || {
+            StoreError::Conflict(format!(
+                "{} changed since version {}",
+                model_name.trim_start_matches("redis_").replace('_', " "),
+                expected_version
+            ))
+        };
+        let client = self.pool.next();
+        crate::utils::time_wrapper(
+            async {
+                let record = client
+                    .hget::<Option<Vec<u8>>, _, _>(key.as_str(), field.as_str())
+                    .await?
+                    .ok_or_else(StoreError::not_found)?;
+                // a stale version is a conflict even when the patch would not apply to the
+                // current record, matching the other backends
+                let record: serde_json::Value = serde_json::from_slice(&record)?;
+                if record["version"].as_i64().unwrap_or(0) != expected_version
+                    || expected_status
+                        .as_ref()
+                        .is_some_and(|status| record["status"].as_str() != Some(status.as_str()))
+                {
+                    return Err(stale());
+                }
+                let mut record: T = serde_json::from_value(record)?;
+                patch(&mut record)?;
+
+                let (keys, args) = self.script_call(
+                    &key,
+                    vec![
+                        RedisValue::from(field),
+                        RedisValue::from(expected_version),
+                        RedisValue::from(Bytes::from(serde_json::to_vec(&record)?)),
+                        RedisValue::from(expected_status.unwrap_or_default()),
+                    ],
+                    mutation,
+                );
+                let written = self
+                    .scripts
+                    .versioned_set
+                    .evalsha_with_reload::<i64, _, _>(client, keys, args)
+                    .await?;
+                match written {
+                    1 => {}
+                    -1 => return Err(StoreError::not_found()),
+                    _ => return Err(stale()),
+                }
+                self.wait_for_replicas(client, model_name).await
+            },
+            model_name,
+            "UPDATE",
+        )
+        .await
+    }
+}
+
+/// Key holding a payment's intent and attempts, scoped by merchant.
+///
+/// In redis this is a hash with the intent under `pi_{payment_id}` and one field per
+/// attempt under `pa_{attempt_id}`, mirroring the cassandra clustering on attempt_id.
+/// The merchant id is prefixed with its length, otherwise merchant `a_pay_b` with payment
+/// `c` and merchant `a` with payment `b_pay_c` would share a hash and each other's attempts.
+/// Hashes under the older `mer_{merchant_id}_pay_{payment_id}` key are moved over by
+/// `store migrate redis-keys`.
+pub(crate) fn payment_key(merchant_id: &str, payment_id: &str) -> String {
+    format!(
+        "mer_{}_{}_pay_{}",
+        merchant_id.len(),
+        merchant_id,
+        payment_id
+    )
+}
+
+fn intent_field(payment_id: &str) -> String {
+    format!("pi_{}", payment_id)
+}
+
+fn attempt_field(attempt_id: &str) -> String {
+    format!("pa_{}", attempt_id)
+}
+
+const ATTEMPT_FIELD_PATTERN: &str = "pa_*";
+
+#[cfg(feature = "cassandra")]
+#[async_trait::async_trait]
+impl PaymentAttemptInterface for CassClient {
+    async fn create_attempt(&self, payment_attempt: PaymentAttempt) -> Result<(), StoreError> {
+        let merchant_id = payment_attempt.merchant_id.as_str();
+        let payment_id = payment_attempt.payment_id.as_str();
+        let payment_intent = self.retrieve_intent(merchant_id, payment_id).await?;
+
+        let mut insert = self
+            .statement(PaymentAttempt::insert_cql(&self.keyspace.name))
+            .await?;
+        payment_attempt.populate_statement(&mut insert)?;
+        self.set_consistency(&mut insert, "payment_attempt", "CREATE")?;
+        let rows = crate::utils::time_wrapper(
+            insert.execute(),
+            &self.latency_label("payment_attempt"),
+            "CREATE",
+        )
+        .await?;
+        if !lwt_applied(&rows)? {
+            return Err(StoreError::AlreadyExists(
+                "payment attempt already exists".to_owned(),
+            ));
+        }
+
+        // lightweight transactions cannot span both tables, so the attempt claimed above is
+        // linked by a second one on the intent version and removed again if that never applies
+        if let Err(err) = self.link_attempt(payment_intent, &payment_attempt).await {
+            if let Err(cleanup) = self.remove_attempt(&payment_attempt).await {
+                // the link error is what the caller needs, the stranded attempt is only reported
+                eprintln!(
+                    "attempt {} left without its intent link: {}",
+                    payment_attempt.attempt_id, cleanup
+                );
+                metrics::counter!("cassandra_orphaned_attempts").increment(1);
+            }
+            return Err(err);
+        }
         Ok(())
     }
 
     async fn retrieve_all<'a>(
         &self,
+        merchant_id: &'a str,
         payment_id: &'a str,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    ) -> Result<Vec<PaymentAttempt>, StoreError> {
         let mut statement = self
-            .cassandra_session
-            .statement(select_payment_attempt_all());
+            .statement(PaymentAttempt::select_cql(&self.keyspace.name))
+            .await?;
 
         statement.bind(0, payment_id)?;
-        statement.bind(1, "kaps")?;
-        statement.set_consistency(cassandra_cpp::Consistency::LOCAL_QUORUM)?;
-        let rows =
-            crate::utils::time_wrapper(statement.execute(), "payment_attempt", "FIND_ALL").await?;
-        //let mut rows = rows.iter();
+        statement.bind(1, merchant_id)?;
+        self.set_consistency(&mut statement, "payment_attempt", "FIND_ALL")?;
+        let rows = crate::utils::time_wrapper(
+            statement.execute(),
+            &self.latency_label("payment_attempt"),
+            "FIND_ALL",
+        )
+        .await?;
 
-        let _ = rows.iter().next().context("No rows found")?;
-        Ok(())
+        let mut payment_attempts = Vec::new();
+        let mut rows = rows.iter();
+        while let Some(row) = rows.next() {
+            payment_attempts.push(PaymentAttempt::from_row(&row)?);
+        }
+        Ok(payment_attempts)
     }
     async fn update_attempt<'a>(
         &self,
-        payment_intent_id: &'a str,
-        version: String,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let mut statement = self.cassandra_session.statement(update_attempt_cql());
-        for_opt(&mut statement, &Some(get_large_value()), 0)?;
-        statement.bind(1, payment_intent_id)?;
-        statement.bind(2, "kaps")?;
-        statement.bind(3, version.as_str())?;
-        statement.set_consistency(cassandra_cpp::Consistency::ONE)?;
-        let _rows =
-            crate::utils::time_wrapper(statement.execute(), "payment_attempt", "UPDATE").await?;
-        Ok(())
+        merchant_id: &'a str,
+        payment_id: &'a str,
+        attempt_id: &'a str,
+        expected_version: i64,
+        payment_attempt_update: PaymentAttemptUpdate,
+    ) -> Result<(), StoreError> {
+        if payment_attempt_update.is_empty() {
+            return Err(StoreError::InvalidRequest("No fields to update".to_owned()));
+        }
+        if payment_attempt_update.status.is_some() {
+            // the transition is checked against the stored status, which the write then requires
+            let payment_attempt = self
+                .retrieve_attempt(merchant_id, payment_id, attempt_id)
+                .await?;
+            if payment_attempt.version != expected_version {
+                return Err(stale("payment_attempt", expected_version));
+            }
+            return self
+                .update_attempt_if_status(
+                    merchant_id,
+                    payment_id,
+                    attempt_id,
+                    expected_version,
+                    payment_attempt.status,
+                    payment_attempt_update,
+                )
+                .await;
+        }
+        self.versioned_update::<PaymentAttempt>(
+            "payment_attempt",
+            payment_attempt_update.columns(),
+            |statement| payment_attempt_update.populate_statement(statement),
+            &[payment_id, merchant_id, attempt_id],
+            expected_version,
+            None,
+        )
+        .await
+    }
+
+    async fn update_attempt_if_status<'a>(
+        &self,
+        merchant_id: &'a str,
+        payment_id: &'a str,
+        attempt_id: &'a str,
+        expected_version: i64,
+        expected_status: AttemptStatus,
+        payment_attempt_update: PaymentAttemptUpdate,
+    ) -> Result<(), StoreError> {
+        if payment_attempt_update.is_empty() {
+            return Err(StoreError::InvalidRequest("No fields to update".to_owned()));
+        }
+        if let Err(err) = payment_attempt_update.check_transition(&expected_status) {
+            // a stale request is a conflict whatever its transition, as on the other backends
+            let payment_attempt = self
+                .retrieve_attempt(merchant_id, payment_id, attempt_id)
+                .await?;
+            if payment_attempt.version != expected_version
+                || payment_attempt.status != expected_status
+            {
+                return Err(stale("payment_attempt", expected_version));
+            }
+            return Err(err);
+        }
+        self.versioned_update::<PaymentAttempt>(
+            "payment_attempt",
+            payment_attempt_update.columns(),
+            |statement| payment_attempt_update.populate_statement(statement),
+            &[payment_id, merchant_id, attempt_id],
+            expected_version,
+            Some(status_text(&expected_status)?),
+        )
+        .await
     }
 }
 
-//TODO: convert to generated statements
-fn retrieve_payment_cql() -> String {
-    "SELECT * from payments.payment_intents WHERE payment_id = ? AND merchant_id = ?;".to_owned()
+/// Statements with a fixed shape, prepared by `CassClient` ahead of the first request.
+#[cfg(feature = "cassandra")]
+pub(crate) fn cassandra_statements(keyspace: &str) -> Vec<String> {
+    vec![
+        PaymentIntent::insert_cql(keyspace),
+        PaymentIntent::batch_insert_cql(keyspace),
+        PaymentIntent::select_cql(keyspace),
+        PaymentIntent::update_if_cql(
+            keyspace,
+            &[PaymentIntent::ATTEMPT_COLUMNS, &["version"]].concat(),
+            &["version"],
+        ),
+        PaymentAttempt::delete_cql(keyspace),
+        PaymentAttempt::insert_cql(keyspace),
+        PaymentAttempt::select_cql(keyspace),
+    ]
 }
 
 #[cfg(feature = "cassandra")]
 #[async_trait::async_trait]
 impl PaymentIntentInterface for CassClient {
-    async fn create_intent(&self, payment_id: String) -> Result<(), Box<dyn std::error::Error>> {
-        let mut statement = self.cassandra_session.statement(insert_intent_cql());
-
-        PaymentIntent::new(payment_id).populate_statement(&mut statement)?;
+    async fn create_intent(&self, payment_intent: PaymentIntent) -> Result<(), StoreError> {
+        let mut statement = self
+            .statement(PaymentIntent::insert_cql(&self.keyspace.name))
+            .await?;
 
-        //println!("what is statement {:?} ", statement);
-        statement.set_consistency(cassandra_cpp::Consistency::ONE)?;
-        let _rows = crate::utils::time_wrapper(statement.execute(), "payment_intent", "CREATE")
-            .await
-            .map_err(|e| {
-                println!("intent create error {}", e.to_string());
-                e
-            })?;
+        payment_intent.populate_statement(&mut statement)?;
+        self.set_consistency(&mut statement, "payment_intent", "CREATE")?;
+        let rows = crate::utils::time_wrapper(
+            statement.execute(),
+            &self.latency_label("payment_intent"),
+            "CREATE",
+        )
+        .await?;
+        if !lwt_applied(&rows)? {
+            return Err(StoreError::AlreadyExists(
+                "payment intent already exists".to_owned(),
+            ));
+        }
         Ok(())
     }
 
     async fn retrieve_intent<'a>(
         &self,
+        merchant_id: &'a str,
         payment_id: &'a str,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let mut statement = self.cassandra_session.statement(retrieve_payment_cql());
+    ) -> Result<PaymentIntent, StoreError> {
+        let mut statement = self
+            .statement(PaymentIntent::select_cql(&self.keyspace.name))
+            .await?;
 
         statement.bind(0, payment_id)?;
-        statement.bind(1, "kaps")?;
-        statement.set_consistency(cassandra_cpp::Consistency::LOCAL_QUORUM)?;
+        statement.bind(1, merchant_id)?;
+        self.set_consistency(&mut statement, "payment_intent", "FIND")?;
 
-        let rows =
-            crate::utils::time_wrapper(statement.execute(), "payment_intent", "FIND").await?;
-        let _row = rows.iter().next().context("No rows found")?;
-        //TODO : deserialize
-        Ok(())
+        let rows = crate::utils::time_wrapper(
+            statement.execute(),
+            &self.latency_label("payment_intent"),
+            "FIND",
+        )
+        .await?;
+        let row = rows.first_row().ok_or_else(StoreError::not_found)?;
+        PaymentIntent::from_row(&row)
     }
 
     async fn update_intent<'a>(
         &self,
-        payment_intent_id: &'a str,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let mut statement = self.cassandra_session.statement(update_intent_cql());
-
-        statement.bind(0, "SUCCESS")?;
-        statement.bind(1, payment_intent_id)?;
+        merchant_id: &'a str,
+        payment_id: &'a str,
+        expected_version: i64,
+        payment_intent_update: PaymentIntentUpdate,
+    ) -> Result<(), StoreError> {
+        if payment_intent_update.is_empty() {
+            return Err(StoreError::InvalidRequest("No fields to update".to_owned()));
+        }
+        if payment_intent_update.status.is_some() {
+            // the transition is checked against the stored status, which the write then requires
+            let payment_intent = self.retrieve_intent(merchant_id, payment_id).await?;
+            if payment_intent.version != expected_version {
+                return Err(stale("payment_intent", expected_version));
+            }
+            return self
+                .update_intent_if_status(
+                    merchant_id,
+                    payment_id,
+                    expected_version,
+                    payment_intent.status,
+                    payment_intent_update,
+                )
+                .await;
+        }
+        self.versioned_update::<PaymentIntent>(
+            "payment_intent",
+            payment_intent_update.columns(),
+            |statement| payment_intent_update.populate_statement(statement),
+            &[payment_id, merchant_id],
+            expected_version,
+            None,
+        )
+        .await
+    }
 
-        statement.bind(2, "kaps")?;
-        statement.set_consistency(cassandra_cpp::Consistency::ONE)?;
+    async fn update_intent_if_status<'a>(
+        &self,
+        merchant_id: &'a str,
+        payment_id: &'a str,
+        expected_version: i64,
+        expected_status: IntentStatus,
+        payment_intent_update: PaymentIntentUpdate,
+    ) -> Result<(), StoreError> {
+        if payment_intent_update.is_empty() {
+            return Err(StoreError::InvalidRequest("No fields to update".to_owned()));
+        }
+        if let Err(err) = payment_intent_update.check_transition(&expected_status) {
+            // a stale request is a conflict whatever its transition, as on the other backends
+            let payment_intent = self.retrieve_intent(merchant_id, payment_id).await?;
+            if payment_intent.version != expected_version
+                || payment_intent.status != expected_status
+            {
+                return Err(stale("payment_intent", expected_version));
+            }
+            return Err(err);
+        }
+        self.versioned_update::<PaymentIntent>(
+            "payment_intent",
+            payment_intent_update.columns(),
+            |statement| payment_intent_update.populate_statement(statement),
+            &[payment_id, merchant_id],
+            expected_version,
+            Some(status_text(&expected_status)?),
+        )
+        .await
+    }
+}
 
-        let _rows =
-            crate::utils::time_wrapper(statement.execute(), "payment_intent", "UPDATE").await?;
+#[cfg(feature = "cassandra")]
+#[async_trait::async_trait]
+impl PaymentImportInterface for CassClient {
+    async fn import_payment(
+        &self,
+        payment_intent: PaymentIntent,
+        payment_attempts: Vec<PaymentAttempt>,
+    ) -> Result<(), StoreError> {
+        let mut batch = self.cassandra_session.batch(BatchType::LOGGED);
+        let mut insert = self
+            .statement(PaymentIntent::batch_insert_cql(&self.keyspace.name))
+            .await?;
+        payment_intent.populate_statement(&mut insert)?;
+        batch.add_statement(insert)?;
+        for payment_attempt in &payment_attempts {
+            let mut insert = self
+                .statement(PaymentAttempt::batch_insert_cql(&self.keyspace.name))
+                .await?;
+            payment_attempt.populate_statement(&mut insert)?;
+            batch.add_statement(insert)?;
+        }
+        self.set_batch_consistency(&mut batch, "payment_intent", "CREATE")?;
+        crate::utils::time_wrapper(
+            batch.execute(),
+            &self.latency_label("payment_intent"),
+            "IMPORT",
+        )
+        .await?;
         Ok(())
     }
 }
 
 #[async_trait::async_trait]
 impl PaymentIntentInterface for RedisClient {
-    async fn create_intent(&self, payment_id: String) -> Result<(), Box<dyn std::error::Error>> {
-        let payment_intent = PaymentIntent::new(payment_id.clone());
-        let client = self.pool.next();
-        crate::utils::time_wrapper(
-            async {
-                client
-                    .hsetnx::<(), _, _, _>(
-                        format!("mer_kaps_pay_{}", payment_id),
-                        format!("pi_{}", payment_id),
-                        serde_json::to_vec(&payment_intent)
-                            .unwrap_or_default()
-                            .as_slice(),
-                    )
-                    .await
-                    .map_err(|err| eprintln!("{:?}", err));
-
-                self.pool.wait(self.replicas, self.timeout).await
-            },
+    async fn create_intent(&self, payment_intent: PaymentIntent) -> Result<(), StoreError> {
+        self.create_field(
             "redis_payment_intent",
-            "INSERT",
+            payment_key(&payment_intent.merchant_id, &payment_intent.payment_id),
+            intent_field(&payment_intent.payment_id),
+            &payment_intent,
+            self.drainer_entry(|| Mutation::CreateIntent {
+                payment_intent: payment_intent.clone(),
+            })?,
         )
-        .await?;
-        Ok(())
+        .await
     }
     async fn retrieve_intent<'a>(
         &self,
+        merchant_id: &'a str,
         payment_id: &'a str,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let key = format!("mer_kaps_pay_{}", payment_id);
-        let field = format!("pi_{}", payment_id);
+    ) -> Result<PaymentIntent, StoreError> {
+        let key = payment_key(merchant_id, payment_id);
+        let field = intent_field(payment_id);
 
         let client = self.pool.next();
-        crate::utils::time_wrapper(
-            client.hget::<(), _, _>(key, field),
+        let payment_intent = crate::utils::time_wrapper(
+            client.hget::<Option<Vec<u8>>, _, _>(key, field),
             "redis_payment_intent",
             "FIND",
         )
-        .await?;
-        Ok(())
+        .await?
+        .ok_or_else(StoreError::not_found)?;
+        Ok(serde_json::from_slice(&payment_intent)?)
     }
 
     async fn update_intent<'a>(
         &self,
+        merchant_id: &'a str,
         payment_id: &'a str,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let mut payment_intent = PaymentIntent::new(payment_id.to_string());
-
-        payment_intent.status = String::from("SUCCESS");
-
-        let client = self.pool.next();
-        crate::utils::time_wrapper(
-            async {
-                client
-                    .hset::<(), _, _>(
-                        format!("mer_kaps_pay_{}", payment_id),
-                        (
-                            format!("pi_{}", payment_id),
-                            serde_json::to_vec(&payment_intent)
-                                .unwrap_or_default()
-                                .as_slice(),
-                        ),
-                    )
-                    .await
-                    .map_err(|err| eprintln!("{:?}", err));
-                self.pool.wait(self.replicas, self.timeout).await
+        expected_version: i64,
+        payment_intent_update: PaymentIntentUpdate,
+    ) -> Result<(), StoreError> {
+        if payment_intent_update.is_empty() {
+            return Err(StoreError::InvalidRequest("No fields to update".to_owned()));
+        }
+        let mutation = self.drainer_entry(|| Mutation::UpdateIntent {
+            merchant_id: merchant_id.to_owned(),
+            payment_id: payment_id.to_owned(),
+            expected_version,
+            expected_status: None,
+            update: payment_intent_update.clone(),
+        })?;
+        self.versioned_update(
+            "redis_payment_intent",
+            payment_key(merchant_id, payment_id),
+            intent_field(payment_id),
+            expected_version,
+            None,
+            |payment_intent: &mut PaymentIntent| {
+                payment_intent_update.check_transition(&payment_intent.status)?;
+                payment_intent_update.apply(payment_intent);
+                payment_intent.version = expected_version + 1;
+                Ok(())
             },
+            mutation,
+        )
+        .await
+    }
+
+    async fn update_intent_if_status<'a>(
+        &self,
+        merchant_id: &'a str,
+        payment_id: &'a str,
+        expected_version: i64,
+        expected_status: IntentStatus,
+        payment_intent_update: PaymentIntentUpdate,
+    ) -> Result<(), StoreError> {
+        if payment_intent_update.is_empty() {
+            return Err(StoreError::InvalidRequest("No fields to update".to_owned()));
+        }
+        let mutation = self.drainer_entry(|| Mutation::UpdateIntent {
+            merchant_id: merchant_id.to_owned(),
+            payment_id: payment_id.to_owned(),
+            expected_version,
+            expected_status: Some(expected_status.clone()),
+            update: payment_intent_update.clone(),
+        })?;
+        self.versioned_update(
             "redis_payment_intent",
-            "UPDATE",
+            payment_key(merchant_id, payment_id),
+            intent_field(payment_id),
+            expected_version,
+            Some(status_text(&expected_status)?),
+            |payment_intent: &mut PaymentIntent| {
+                payment_intent_update.check_transition(&payment_intent.status)?;
+                payment_intent_update.apply(payment_intent);
+                payment_intent.version = expected_version + 1;
+                Ok(())
+            },
+            mutation,
         )
-        .await?;
-        Ok(())
+        .await
     }
 }
 
 #[async_trait::async_trait]
 impl PaymentAttemptInterface for RedisClient {
-    async fn create_attempt(
-        &self,
-        payment_id: String,
-        version: String,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let payment_attempt = PaymentAttempt::new(payment_id.clone(), version);
-
+    async fn create_attempt(&self, payment_attempt: PaymentAttempt) -> Result<(), StoreError> {
+        let key = payment_key(&payment_attempt.merchant_id, &payment_attempt.payment_id);
+        let intent_field = intent_field(&payment_attempt.payment_id);
+        let attempt = serde_json::to_vec(&payment_attempt)?;
+        let mutation = self.drainer_entry(|| Mutation::CreateAttempt {
+            payment_attempt: payment_attempt.clone(),
+        })?;
         let client = self.pool.next();
         crate::utils::time_wrapper(
             async {
-                client
-                    .hsetnx::<(), _, _, _>(
-                        format!("mer_kaps_pay_{}", payment_id),
-                        format!("pa_{}", payment_id),
-                        serde_json::to_vec(&payment_attempt)
-                            .unwrap_or_default()
-                            .as_slice(),
-                    )
-                    .await
-                    .map_err(|err| eprintln!("{:?}", err));
-                self.pool.wait(self.replicas, self.timeout).await
+                let payment_intent = client
+                    .hget::<Option<Vec<u8>>, _, _>(key.as_str(), intent_field.as_str())
+                    .await?
+                    .ok_or_else(StoreError::not_found)?;
+                let mut payment_intent: PaymentIntent = serde_json::from_slice(&payment_intent)?;
+                let expected_version = payment_intent.version;
+                payment_intent.record_attempt(&payment_attempt.attempt_id);
+
+                let (keys, args) = self.script_call(
+                    &key,
+                    vec![
+                        RedisValue::from(intent_field.as_str()),
+                        RedisValue::from(expected_version),
+                        RedisValue::from(Bytes::from(serde_json::to_vec(&payment_intent)?)),
+                        RedisValue::from(attempt_field(&payment_attempt.attempt_id)),
+                        RedisValue::from(Bytes::from(attempt)),
+                    ],
+                    mutation,
+                );
+                let written = self
+                    .scripts
+                    .create_attempt
+                    .evalsha_with_reload::<i64, _, _>(client, keys, args)
+                    .await?;
+                match written {
+                    1 => {}
+                    0 => {
+                        return Err(StoreError::AlreadyExists(
+                            "payment attempt already exists".to_owned(),
+                        ))
+                    }
+                    -1 => return Err(StoreError::not_found()),
+                    _ => {
+                        return Err(StoreError::Conflict(format!(
+                            "payment intent changed since version {}",
+                            expected_version
+                        )))
+                    }
+                }
+                self.wait_for_replicas(client, "redis_payment_attempt").await
             },
             "redis_payment_attempt",
             "INSERT",
         )
-        .await?;
-        Ok(())
+        .await
     }
 
     async fn retrieve_all<'a>(
         &self,
+        merchant_id: &'a str,
         payment_id: &'a str,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    ) -> Result<Vec<PaymentAttempt>, StoreError> {
         let client = self.pool.next();
-        crate::utils::time_wrapper(
+        let pages = crate::utils::time_wrapper(
             client
                 .hscan::<&str, &str>(
-                    format!("mer_kaps_pay_{}", payment_id).as_str(),
-                    "pa_*",
+                    payment_key(merchant_id, payment_id).as_str(),
+                    ATTEMPT_FIELD_PATTERN,
                     None,
                 )
-                .map(|val| val)
+                .map(|page| {
+                    let mut page = page?;
+                    let values = page.take_results();
+                    page.next()?;
+                    Ok::<_, fred::error::RedisError>(values)
+                })
                 .collect::<Vec<_>>(),
             "redis_payment_attempt",
             "FIND_ALL",
         )
         .await;
-        Ok(())
+
+        let mut payment_attempts = Vec::new();
+        for page in pages {
+            for (_, value) in page?.map(|values| values.inner()).unwrap_or_default() {
+                let value = value.as_bytes().ok_or_else(|| {
+                    StoreError::Serialization("payment attempt is not a string".to_owned())
+                })?;
+                payment_attempts.push(serde_json::from_slice(value)?);
+            }
+        }
+        Ok(payment_attempts)
     }
     async fn update_attempt<'a>(
         &self,
-        payment_intent_id: &'a str,
-        version: String,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let mut payment_attempt = PaymentAttempt::new(payment_intent_id.to_string(), version);
+        merchant_id: &'a str,
+        payment_id: &'a str,
+        attempt_id: &'a str,
+        expected_version: i64,
+        payment_attempt_update: PaymentAttemptUpdate,
+    ) -> Result<(), StoreError> {
+        if payment_attempt_update.is_empty() {
+            return Err(StoreError::InvalidRequest("No fields to update".to_owned()));
+        }
+        let mutation = self.drainer_entry(|| Mutation::UpdateAttempt {
+            merchant_id: merchant_id.to_owned(),
+            payment_id: payment_id.to_owned(),
+            attempt_id: attempt_id.to_owned(),
+            expected_version,
+            expected_status: None,
+            update: payment_attempt_update.clone(),
+        })?;
+        self.versioned_update(
+            "redis_payment_attempt",
+            payment_key(merchant_id, payment_id),
+            attempt_field(attempt_id),
+            expected_version,
+            None,
+            |payment_attempt: &mut PaymentAttempt| {
+                payment_attempt_update.check_transition(&payment_attempt.status)?;
+                payment_attempt_update.apply(payment_attempt);
+                payment_attempt.version = expected_version + 1;
+                Ok(())
+            },
+            mutation,
+        )
+        .await
+    }
 
-        payment_attempt.status = AttemptStatus::Charged;
+    async fn update_attempt_if_status<'a>(
+        &self,
+        merchant_id: &'a str,
+        payment_id: &'a str,
+        attempt_id: &'a str,
+        expected_version: i64,
+        expected_status: AttemptStatus,
+        payment_attempt_update: PaymentAttemptUpdate,
+    ) -> Result<(), StoreError> {
+        if payment_attempt_update.is_empty() {
+            return Err(StoreError::InvalidRequest("No fields to update".to_owned()));
+        }
+        let mutation = self.drainer_entry(|| Mutation::UpdateAttempt {
+            merchant_id: merchant_id.to_owned(),
+            payment_id: payment_id.to_owned(),
+            attempt_id: attempt_id.to_owned(),
+            expected_version,
+            expected_status: Some(expected_status.clone()),
+            update: payment_attempt_update.clone(),
+        })?;
+        self.versioned_update(
+            "redis_payment_attempt",
+            payment_key(merchant_id, payment_id),
+            attempt_field(attempt_id),
+            expected_version,
+            Some(status_text(&expected_status)?),
+            |payment_attempt: &mut PaymentAttempt| {
+                payment_attempt_update.check_transition(&payment_attempt.status)?;
+                payment_attempt_update.apply(payment_attempt);
+                payment_attempt.version = expected_version + 1;
+                Ok(())
+            },
+            mutation,
+        )
+        .await
+    }
+}
 
+#[async_trait::async_trait]
+impl PaymentImportInterface for RedisClient {
+    async fn import_payment(
+        &self,
+        payment_intent: PaymentIntent,
+        payment_attempts: Vec<PaymentAttempt>,
+    ) -> Result<(), StoreError> {
+        let key = payment_key(&payment_intent.merchant_id, &payment_intent.payment_id);
+        let mut fields = vec![(
+            intent_field(&payment_intent.payment_id),
+            Bytes::from(serde_json::to_vec(&payment_intent)?),
+        )];
+        for payment_attempt in &payment_attempts {
+            fields.push((
+                attempt_field(&payment_attempt.attempt_id),
+                Bytes::from(serde_json::to_vec(payment_attempt)?),
+            ));
+        }
+        // not appended to the drainer stream, a hybrid target is copied into separately
         let client = self.pool.next();
         crate::utils::time_wrapper(
             async {
-                client
-                    .hset::<(), _, _>(
-                        format!("mer_kaps_pay_{}", payment_intent_id),
-                        (
-                            format!("pi_{}", payment_intent_id),
-                            serde_json::to_vec(&payment_attempt)
-                                .unwrap_or_default()
-                                .as_slice(),
-                        ),
-                    )
-                    .await
-                    .map_err(|err| eprintln!("{:?}", err));
-                self.pool.wait(self.replicas, self.timeout).await
+                client.hset::<(), _, _>(key, fields).await?;
+                self.wait_for_replicas(client, "redis_payment_intent").await
             },
             "redis_payment_intent",
+            "IMPORT",
+        )
+        .await
+    }
+}
+
+impl InMemoryStore {
+    async fn update_intent_matching(
+        &self,
+        merchant_id: &str,
+        payment_id: &str,
+        expected_version: i64,
+        expected_status: Option<IntentStatus>,
+        payment_intent_update: PaymentIntentUpdate,
+    ) -> Result<(), StoreError> {
+        if payment_intent_update.is_empty() {
+            return Err(StoreError::InvalidRequest("No fields to update".to_owned()));
+        }
+        crate::utils::time_wrapper(
+            async {
+                let mut payment_intent = self
+                    .intents
+                    .get_mut(&payment_key(merchant_id, payment_id))
+                    .ok_or_else(StoreError::not_found)?;
+                if payment_intent.version != expected_version
+                    || expected_status.is_some_and(|status| payment_intent.status != status)
+                {
+                    return Err(StoreError::Conflict(format!(
+                        "payment intent changed since version {}",
+                        expected_version
+                    )));
+                }
+                payment_intent_update.check_transition(&payment_intent.status)?;
+                payment_intent_update.apply(&mut payment_intent);
+                payment_intent.version += 1;
+                Ok(())
+            },
+            "memory_payment_intent",
             "UPDATE",
         )
-        .await?;
+        .await
+    }
+
+    async fn update_attempt_matching(
+        &self,
+        merchant_id: &str,
+        payment_id: &str,
+        attempt_id: &str,
+        expected_version: i64,
+        expected_status: Option<AttemptStatus>,
+        payment_attempt_update: PaymentAttemptUpdate,
+    ) -> Result<(), StoreError> {
+        if payment_attempt_update.is_empty() {
+            return Err(StoreError::InvalidRequest("No fields to update".to_owned()));
+        }
+        crate::utils::time_wrapper(
+            async {
+                let mut attempts = self
+                    .attempts
+                    .get_mut(&payment_key(merchant_id, payment_id))
+                    .ok_or_else(StoreError::not_found)?;
+                let payment_attempt = attempts
+                    .get_mut(attempt_id)
+                    .ok_or_else(StoreError::not_found)?;
+                if payment_attempt.version != expected_version
+                    || expected_status.is_some_and(|status| payment_attempt.status != status)
+                {
+                    return Err(StoreError::Conflict(format!(
+                        "payment attempt changed since version {}",
+                        expected_version
+                    )));
+                }
+                payment_attempt_update.check_transition(&payment_attempt.status)?;
+                payment_attempt_update.apply(payment_attempt);
+                payment_attempt.version += 1;
+                Ok(())
+            },
+            "memory_payment_attempt",
+            "UPDATE",
+        )
+        .await
+    }
+}
+
+#[async_trait::async_trait]
+impl PaymentIntentInterface for InMemoryStore {
+    async fn create_intent(&self, payment_intent: PaymentIntent) -> Result<(), StoreError> {
+        crate::utils::time_wrapper(
+            async {
+                match self.intents.entry(payment_key(
+                    &payment_intent.merchant_id,
+                    &payment_intent.payment_id,
+                )) {
+                    Entry::Occupied(_) => Err(StoreError::AlreadyExists(
+                        "payment intent already exists".to_owned(),
+                    )),
+                    Entry::Vacant(entry) => {
+                        entry.insert(payment_intent);
+                        Ok(())
+                    }
+                }
+            },
+            "memory_payment_intent",
+            "INSERT",
+        )
+        .await
+    }
+
+    async fn retrieve_intent<'a>(
+        &self,
+        merchant_id: &'a str,
+        payment_id: &'a str,
+    ) -> Result<PaymentIntent, StoreError> {
+        let payment_intent = crate::utils::time_wrapper(
+            async {
+                self.intents
+                    .get(&payment_key(merchant_id, payment_id))
+                    .map(|intent| intent.clone())
+            },
+            "memory_payment_intent",
+            "FIND",
+        )
+        .await
+        .ok_or_else(StoreError::not_found)?;
+        Ok(payment_intent)
+    }
+
+    async fn update_intent<'a>(
+        &self,
+        merchant_id: &'a str,
+        payment_id: &'a str,
+        expected_version: i64,
+        payment_intent_update: PaymentIntentUpdate,
+    ) -> Result<(), StoreError> {
+        self.update_intent_matching(
+            merchant_id,
+            payment_id,
+            expected_version,
+            None,
+            payment_intent_update,
+        )
+        .await
+    }
+
+    async fn update_intent_if_status<'a>(
+        &self,
+        merchant_id: &'a str,
+        payment_id: &'a str,
+        expected_version: i64,
+        expected_status: IntentStatus,
+        payment_intent_update: PaymentIntentUpdate,
+    ) -> Result<(), StoreError> {
+        self.update_intent_matching(
+            merchant_id,
+            payment_id,
+            expected_version,
+            Some(expected_status),
+            payment_intent_update,
+        )
+        .await
+    }
+}
+
+#[async_trait::async_trait]
+impl PaymentAttemptInterface for InMemoryStore {
+    async fn create_attempt(&self, payment_attempt: PaymentAttempt) -> Result<(), StoreError> {
+        crate::utils::time_wrapper(
+            async {
+                let key = payment_key(&payment_attempt.merchant_id, &payment_attempt.payment_id);
+                // the intent stays locked until the attempt is in, so readers see both or neither
+                let mut payment_intent = self
+                    .intents
+                    .get_mut(&key)
+                    .ok_or_else(StoreError::not_found)?;
+                let mut attempts = self.attempts.entry(key).or_default();
+                match attempts.entry(payment_attempt.attempt_id.clone()) {
+                    btree_map::Entry::Occupied(_) => Err(StoreError::AlreadyExists(
+                        "payment attempt already exists".to_owned(),
+                    )),
+                    btree_map::Entry::Vacant(entry) => {
+                        payment_intent.record_attempt(&payment_attempt.attempt_id);
+                        entry.insert(payment_attempt);
+                        Ok(())
+                    }
+                }
+            },
+            "memory_payment_attempt",
+            "INSERT",
+        )
+        .await
+    }
+
+    async fn retrieve_all<'a>(
+        &self,
+        merchant_id: &'a str,
+        payment_id: &'a str,
+    ) -> Result<Vec<PaymentAttempt>, StoreError> {
+        let payment_attempts = crate::utils::time_wrapper(
+            async {
+                self.attempts
+                    .get(&payment_key(merchant_id, payment_id))
+                    .map(|attempts| attempts.values().cloned().collect())
+                    .unwrap_or_default()
+            },
+            "memory_payment_attempt",
+            "FIND_ALL",
+        )
+        .await;
+        Ok(payment_attempts)
+    }
+
+    async fn update_attempt<'a>(
+        &self,
+        merchant_id: &'a str,
+        payment_id: &'a str,
+        attempt_id: &'a str,
+        expected_version: i64,
+        payment_attempt_update: PaymentAttemptUpdate,
+    ) -> Result<(), StoreError> {
+        self.update_attempt_matching(
+            merchant_id,
+            payment_id,
+            attempt_id,
+            expected_version,
+            None,
+            payment_attempt_update,
+        )
+        .await
+    }
+
+    async fn update_attempt_if_status<'a>(
+        &self,
+        merchant_id: &'a str,
+        payment_id: &'a str,
+        attempt_id: &'a str,
+        expected_version: i64,
+        expected_status: AttemptStatus,
+        payment_attempt_update: PaymentAttemptUpdate,
+    ) -> Result<(), StoreError> {
+        self.update_attempt_matching(
+            merchant_id,
+            payment_id,
+            attempt_id,
+            expected_version,
+            Some(expected_status),
+            payment_attempt_update,
+        )
+        .await
+    }
+}
+
+#[async_trait::async_trait]
+impl PaymentImportInterface for InMemoryStore {
+    async fn import_payment(
+        &self,
+        payment_intent: PaymentIntent,
+        payment_attempts: Vec<PaymentAttempt>,
+    ) -> Result<(), StoreError> {
+        crate::utils::time_wrapper(
+            async {
+                let key = payment_key(&payment_intent.merchant_id, &payment_intent.payment_id);
+                self.attempts.entry(key.clone()).or_default().extend(
+                    payment_attempts.into_iter().map(|payment_attempt| {
+                        (payment_attempt.attempt_id.clone(), payment_attempt)
+                    }),
+                );
+                self.intents.insert(key, payment_intent);
+            },
+            "memory_payment_intent",
+            "IMPORT",
+        )
+        .await;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::StatusCode;
+
+    async fn store_with_attempt(status: AttemptStatus) -> InMemoryStore {
+        let store = InMemoryStore::new();
+        store
+            .create_intent(PaymentIntent::new("p1".to_owned(), "m1".to_owned()))
+            .await
+            .unwrap();
+        let mut payment_attempt =
+            PaymentAttempt::new("p1".to_owned(), "m1".to_owned(), "1".to_owned());
+        payment_attempt.status = status;
+        store.create_attempt(payment_attempt).await.unwrap();
+        store
+    }
+
+    /// Moves the only attempt to `status` from the version it is at now.
+    async fn move_attempt(store: &InMemoryStore, status: AttemptStatus) -> Result<(), StoreError> {
+        let attempt_id = PaymentAttempt::attempt_id_for("p1", "1");
+        let version = store.retrieve_all("m1", "p1").await?[0].version;
+        let payment_attempt_update = PaymentAttemptUpdate {
+            status: Some(status),
+            ..Default::default()
+        };
+        store
+            .update_attempt("m1", "p1", &attempt_id, version, payment_attempt_update)
+            .await
+    }
+
+    async fn attempt_status(store: &InMemoryStore) -> AttemptStatus {
+        store.retrieve_all("m1", "p1").await.unwrap()[0].status.clone()
+    }
+
+    #[test]
+    fn payment_keys_do_not_collide_across_merchants() {
+        assert_ne!(payment_key("a_pay_b", "c"), payment_key("a", "b_pay_c"));
+    }
+
+    #[tokio::test]
+    async fn create_attempt_links_the_intent() {
+        let store = store_with_attempt(AttemptStatus::Started).await;
+        let payment_intent = store.retrieve_intent("m1", "p1").await.unwrap();
+        let created = PaymentIntent::new("p1".to_owned(), "m1".to_owned());
+        assert_eq!(
+            payment_intent.active_attempt_id,
+            PaymentAttempt::attempt_id_for("p1", "1")
+        );
+        assert_eq!(payment_intent.attempt_count, created.attempt_count + 1);
+        assert_eq!(payment_intent.version, created.version + 1);
+    }
+
+    #[tokio::test]
+    async fn attempt_follows_authorize_then_charge() {
+        let store = store_with_attempt(AttemptStatus::Started).await;
+        move_attempt(&store, AttemptStatus::Authorized)
+            .await
+            .unwrap();
+        move_attempt(&store, AttemptStatus::Charged).await.unwrap();
+        assert_eq!(attempt_status(&store).await, AttemptStatus::Charged);
+    }
+
+    #[tokio::test]
+    async fn charged_attempt_cannot_restart() {
+        let store = store_with_attempt(AttemptStatus::Charged).await;
+        let err = move_attempt(&store, AttemptStatus::Started)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, StoreError::InvalidTransition(_)));
+        assert_eq!(err.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(attempt_status(&store).await, AttemptStatus::Charged);
+    }
+
+    #[tokio::test]
+    async fn stale_version_conflicts_before_the_transition_check() {
+        let store = store_with_attempt(AttemptStatus::Charged).await;
+        let attempt_id = PaymentAttempt::attempt_id_for("p1", "1");
+        let payment_attempt_update = PaymentAttemptUpdate {
+            status: Some(AttemptStatus::Started),
+            ..Default::default()
+        };
+        let err = store
+            .update_attempt("m1", "p1", &attempt_id, 0, payment_attempt_update)
+            .await
+            .unwrap_err();
+        assert_eq!(err.status_code(), StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn terminal_attempt_rejects_every_move() {
+        let store = store_with_attempt(AttemptStatus::Failure).await;
+        for next in [
+            AttemptStatus::Started,
+            AttemptStatus::Authorized,
+            AttemptStatus::Charged,
+        ] {
+            let err = move_attempt(&store, next).await.unwrap_err();
+            assert!(matches!(err, StoreError::InvalidTransition(_)));
+        }
+    }
+
+    #[tokio::test]
+    async fn same_status_is_allowed() {
+        let store = store_with_attempt(AttemptStatus::Failure).await;
+        move_attempt(&store, AttemptStatus::Failure).await.unwrap();
+
+        let payment_intent_update = PaymentIntentUpdate {
+            status: Some(IntentStatus::Processing),
+            ..Default::default()
+        };
+        let version = store.retrieve_intent("m1", "p1").await.unwrap().version;
+        store
+            .update_intent("m1", "p1", version, payment_intent_update)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn succeeded_intent_is_terminal() {
+        let store = InMemoryStore::new();
+        store
+            .create_intent(PaymentIntent::new("p1".to_owned(), "m1".to_owned()))
+            .await
+            .unwrap();
+        let update = |status| PaymentIntentUpdate {
+            status: Some(status),
+            ..Default::default()
+        };
+        store
+            .update_intent("m1", "p1", 1, update(IntentStatus::Succeeded))
+            .await
+            .unwrap();
+        let err = store
+            .update_intent("m1", "p1", 2, update(IntentStatus::Processing))
+            .await
+            .unwrap_err();
+        assert_eq!(err.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+}
@@ -0,0 +1,21 @@
+-- KEYS[1] payment hash, KEYS[2] drainer stream in hybrid mode,
+-- ARGV[1] intent field, ARGV[2] expected intent version,
+-- ARGV[3] updated intent, ARGV[4] attempt field, ARGV[5] new attempt,
+-- ARGV[6] drainer mutation in hybrid mode
+-- returns 1 when both are written, 0 when the attempt exists, -1 when the intent is missing,
+-- -2 when the intent changed since it was read
+if redis.call('HEXISTS', KEYS[1], ARGV[4]) == 1 then
+  return 0
+end
+local current = redis.call('HGET', KEYS[1], ARGV[1])
+if not current then
+  return -1
+end
+if (cjson.decode(current)['version'] or 0) ~= tonumber(ARGV[2]) then
+  return -2
+end
+redis.call('HSET', KEYS[1], ARGV[1], ARGV[3], ARGV[4], ARGV[5])
+if KEYS[2] then
+  redis.call('XADD', KEYS[2], '*', 'mutation', ARGV[6])
+end
+return 1
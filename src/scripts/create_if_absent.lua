@@ -0,0 +1,11 @@
+-- KEYS[1] payment hash, KEYS[2] drainer stream in hybrid mode,
+-- ARGV[1] field, ARGV[2] new record, ARGV[3] drainer mutation in hybrid mode
+-- returns 1 when written, 0 when the field already exists
+if redis.call('HEXISTS', KEYS[1], ARGV[1]) == 1 then
+  return 0
+end
+redis.call('HSET', KEYS[1], ARGV[1], ARGV[2])
+if KEYS[2] then
+  redis.call('XADD', KEYS[2], '*', 'mutation', ARGV[3])
+end
+return 1
@@ -0,0 +1,20 @@
+-- KEYS[1] payment hash, KEYS[2] drainer stream in hybrid mode,
+-- ARGV[1] field, ARGV[2] expected version, ARGV[3] new record, ARGV[4] expected status or '',
+-- ARGV[5] drainer mutation in hybrid mode
+-- returns 1 when written, 0 on a version or status mismatch, -1 when the field is missing
+local current = redis.call('HGET', KEYS[1], ARGV[1])
+if not current then
+  return -1
+end
+local record = cjson.decode(current)
+if (record['version'] or 0) ~= tonumber(ARGV[2]) then
+  return 0
+end
+if ARGV[4] ~= '' and record['status'] ~= ARGV[4] then
+  return 0
+end
+redis.call('HSET', KEYS[1], ARGV[1], ARGV[3])
+if KEYS[2] then
+  redis.call('XADD', KEYS[2], '*', 'mutation', ARGV[5])
+end
+return 1
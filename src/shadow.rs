@@ -0,0 +1,527 @@
+use crate::errors::StoreError;
+use crate::models::{
+    payment_key, PaymentAttemptInterface, PaymentImportInterface, PaymentIntentInterface,
+};
+use crate::store::{create_backend, Init, MigrationStatus, StorageInterface};
+use crate::types::*;
+use dashmap::DashMap;
+use futures::future::{FutureExt, Shared};
+use serde_json::Value;
+use std::env;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+/// Resolves once a secondary call has finished, whether it succeeded or not.
+type Done = Shared<oneshot::Receiver<()>>;
+
+/// Sends every call to a primary and a secondary backend and answers with the primary.
+///
+/// The secondary runs in a spawned task, so a slow or stuck secondary costs the request
+/// nothing and is only waited on for `timeout` before being counted. Its latency goes to
+/// `shadow_secondary_latency` and each call is counted in `shadow_comparisons` as `match`,
+/// `mismatch`, `secondary_error` or `timeout`.
+///
+/// Secondary calls on the same payment run one after another in the order they were made,
+/// so a create is in before the attempt or update that follows it reaches the secondary.
+pub struct ShadowStore {
+    pub primary: Box<dyn StorageInterface>,
+    pub secondary: Box<dyn StorageInterface>,
+    pub timeout: Duration,
+    /// The last secondary call queued for each payment, awaited by the next one.
+    pub in_flight: Arc<DashMap<String, Done>>,
+}
+
+impl Clone for ShadowStore {
+    fn clone(&self) -> Self {
+        Self {
+            primary: dyn_clone::clone_box(&*self.primary),
+            secondary: dyn_clone::clone_box(&*self.secondary),
+            timeout: self.timeout,
+            in_flight: self.in_flight.clone(),
+        }
+    }
+}
+
+/// `STORE_BACKEND=shadow`: `SHADOW_PRIMARY` stays authoritative while `SHADOW_SECONDARY`
+/// receives the same traffic, compared for at most `SHADOW_TIMEOUT_MS` (default 1000).
+pub async fn shadow() -> Result<ShadowStore, Box<dyn std::error::Error>> {
+    let primary = env::var("SHADOW_PRIMARY").map_err(|_| "SHADOW_PRIMARY not set")?;
+    let secondary = env::var("SHADOW_SECONDARY").map_err(|_| "SHADOW_SECONDARY not set")?;
+    if primary == "shadow" || secondary == "shadow" {
+        return Err("SHADOW_PRIMARY and SHADOW_SECONDARY cannot be shadow".into());
+    }
+    let timeout = env::var("SHADOW_TIMEOUT_MS").unwrap_or_else(|_| "1000".to_owned());
+    Ok(ShadowStore {
+        primary: Box::pin(create_backend(&primary)).await?,
+        secondary: Box::pin(create_backend(&secondary)).await?,
+        timeout: Duration::from_millis(timeout.parse()?),
+        in_flight: Arc::new(DashMap::new()),
+    })
+}
+
+impl ShadowStore {
+    /// Awaits `primary` and answers with it. `secondary` is handed its own handle on the
+    /// secondary backend and spawned together with the comparison, so neither holds up the
+    /// caller. It starts once the previous secondary call on `key` is done. A secondary that
+    /// overruns `timeout` is counted as `timeout` but left to finish, dropping a `JoinHandle`
+    /// detaches the task rather than cancelling its write.
+    async fn mirror<T, P, F, S>(
+        &self,
+        model_name: &'static str,
+        operation: &'static str,
+        key: String,
+        primary: P,
+        secondary: F,
+    ) -> Result<T, StoreError>
+    where
+        T: serde::Serialize + Send + 'static,
+        P: Future<Output = Result<T, StoreError>>,
+        F: FnOnce(Box<dyn StorageInterface>) -> S,
+        S: Future<Output = Result<T, StoreError>> + Send + 'static,
+    {
+        let secondary = secondary(dyn_clone::clone_box(&*self.secondary));
+        // queued before the primary runs, so the caller's next call is always queued behind it
+        let (done, finished) = oneshot::channel::<()>();
+        let finished = finished.shared();
+        let previous = self.in_flight.insert(key.clone(), finished.clone());
+        let in_flight = self.in_flight.clone();
+        let mut secondary = tokio::spawn(async move {
+            if let Some(previous) = previous {
+                let _ = previous.await;
+            }
+            let start = tokio::time::Instant::now();
+            let result = secondary.await;
+            drop(done);
+            in_flight.remove_if(&key, |_, last| last.ptr_eq(&finished));
+            metrics::histogram!(
+                "shadow_secondary_latency",
+                &[("model", model_name), ("operation", operation)]
+            )
+            .record(start.elapsed().as_secs_f64() * 1000_f64);
+            result
+        });
+        let primary = primary.await;
+
+        let expected = match &primary {
+            Ok(record) => Ok(serde_json::to_value(record).ok()),
+            Err(err) => Err(std::mem::discriminant(err)),
+        };
+        let timeout = self.timeout;
+        tokio::spawn(async move {
+            let outcome = match tokio::time::timeout(timeout, &mut secondary).await {
+                Err(_elapsed) => "timeout",
+                Ok(Err(err)) => {
+                    eprintln!("shadow {} {} panicked: {}", model_name, operation, err);
+                    "secondary_error"
+                }
+                Ok(Ok(secondary)) => match (expected, secondary) {
+                    (Ok(Some(primary)), Ok(secondary)) if same_record(&primary, &secondary) => {
+                        "match"
+                    }
+                    (Err(primary), Err(secondary))
+                        if primary == std::mem::discriminant(&secondary) =>
+                    {
+                        "match"
+                    }
+                    (Ok(_), Err(err)) => {
+                        eprintln!("shadow {} {} failed: {}", model_name, operation, err);
+                        "secondary_error"
+                    }
+                    _ => "mismatch",
+                },
+            };
+            metrics::counter!(
+                "shadow_comparisons",
+                &[
+                    ("model", model_name),
+                    ("operation", operation),
+                    ("outcome", outcome)
+                ]
+            )
+            .increment(1);
+        });
+        primary
+    }
+}
+
+fn same_record<T: serde::Serialize>(primary: &Value, secondary: &T) -> bool {
+    match serde_json::to_value(secondary) {
+        Ok(mut secondary) => {
+            let mut primary = primary.clone();
+            normalize(&mut primary);
+            normalize(&mut secondary);
+            primary == secondary
+        }
+        _ => false,
+    }
+}
+
+/// Evens out what legitimately differs between backends: cassandra keeps timestamps to the
+/// millisecond and returns attempts in clustering order, redis in hash order.
+fn normalize(value: &mut Value) {
+    match value {
+        Value::String(text) => {
+            if let Ok(timestamp) =
+                serde_json::from_value::<time::PrimitiveDateTime>(Value::String(text.clone()))
+            {
+                let millis = timestamp.nanosecond() / 1_000_000 * 1_000_000;
+                if let Ok(Value::String(truncated)) = timestamp
+                    .replace_nanosecond(millis)
+                    .map(serde_json::to_value)
+                    .unwrap_or(Ok(Value::Null))
+                {
+                    *text = truncated;
+                }
+            }
+        }
+        Value::Array(items) => {
+            items.iter_mut().for_each(normalize);
+            items.sort_by(|a, b| a["attempt_id"].to_string().cmp(&b["attempt_id"].to_string()));
+        }
+        Value::Object(fields) => fields.values_mut().for_each(normalize),
+        _ => {}
+    }
+}
+
+#[async_trait::async_trait]
+impl PaymentIntentInterface for ShadowStore {
+    async fn create_intent(&self, payment_intent: PaymentIntent) -> Result<(), StoreError> {
+        self.mirror(
+            "payment_intent",
+            "INSERT",
+            payment_key(&payment_intent.merchant_id, &payment_intent.payment_id),
+            self.primary.create_intent(payment_intent.clone()),
+            |secondary| async move { secondary.create_intent(payment_intent).await },
+        )
+        .await
+    }
+
+    async fn retrieve_intent<'a>(
+        &self,
+        merchant_id: &'a str,
+        payment_id: &'a str,
+    ) -> Result<PaymentIntent, StoreError> {
+        let (owned_merchant_id, owned_payment_id) = (merchant_id.to_owned(), payment_id.to_owned());
+        self.mirror(
+            "payment_intent",
+            "FIND",
+            payment_key(merchant_id, payment_id),
+            self.primary.retrieve_intent(merchant_id, payment_id),
+            |secondary| async move {
+                secondary
+                    .retrieve_intent(&owned_merchant_id, &owned_payment_id)
+                    .await
+            },
+        )
+        .await
+    }
+
+    async fn update_intent<'a>(
+        &self,
+        merchant_id: &'a str,
+        payment_id: &'a str,
+        expected_version: i64,
+        payment_intent_update: PaymentIntentUpdate,
+    ) -> Result<(), StoreError> {
+        let (owned_merchant_id, owned_payment_id) = (merchant_id.to_owned(), payment_id.to_owned());
+        self.mirror(
+            "payment_intent",
+            "UPDATE",
+            payment_key(merchant_id, payment_id),
+            self.primary.update_intent(
+                merchant_id,
+                payment_id,
+                expected_version,
+                payment_intent_update.clone(),
+            ),
+            |secondary| async move {
+                secondary
+                    .update_intent(
+                        &owned_merchant_id,
+                        &owned_payment_id,
+                        expected_version,
+                        payment_intent_update,
+                    )
+                    .await
+            },
+        )
+        .await
+    }
+
+    async fn update_intent_if_status<'a>(
+        &self,
+        merchant_id: &'a str,
+        payment_id: &'a str,
+        expected_version: i64,
+        expected_status: IntentStatus,
+        payment_intent_update: PaymentIntentUpdate,
+    ) -> Result<(), StoreError> {
+        let (owned_merchant_id, owned_payment_id) = (merchant_id.to_owned(), payment_id.to_owned());
+        self.mirror(
+            "payment_intent",
+            "UPDATE",
+            payment_key(merchant_id, payment_id),
+            self.primary.update_intent_if_status(
+                merchant_id,
+                payment_id,
+                expected_version,
+                expected_status.clone(),
+                payment_intent_update.clone(),
+            ),
+            |secondary| async move {
+                secondary
+                    .update_intent_if_status(
+                        &owned_merchant_id,
+                        &owned_payment_id,
+                        expected_version,
+                        expected_status,
+                        payment_intent_update,
+                    )
+                    .await
+            },
+        )
+        .await
+    }
+}
+
+#[async_trait::async_trait]
+impl PaymentAttemptInterface for ShadowStore {
+    async fn create_attempt(&self, payment_attempt: PaymentAttempt) -> Result<(), StoreError> {
+        self.mirror(
+            "payment_attempt",
+            "INSERT",
+            payment_key(&payment_attempt.merchant_id, &payment_attempt.payment_id),
+            self.primary.create_attempt(payment_attempt.clone()),
+            |secondary| async move { secondary.create_attempt(payment_attempt).await },
+        )
+        .await
+    }
+
+    async fn retrieve_all<'a>(
+        &self,
+        merchant_id: &'a str,
+        payment_id: &'a str,
+    ) -> Result<Vec<PaymentAttempt>, StoreError> {
+        let (owned_merchant_id, owned_payment_id) = (merchant_id.to_owned(), payment_id.to_owned());
+        self.mirror(
+            "payment_attempt",
+            "FIND_ALL",
+            payment_key(merchant_id, payment_id),
+            self.primary.retrieve_all(merchant_id, payment_id),
+            |secondary| async move {
+                secondary
+                    .retrieve_all(&owned_merchant_id, &owned_payment_id)
+                    .await
+            },
+        )
+        .await
+    }
+
+    async fn update_attempt<'a>(
+        &self,
+        merchant_id: &'a str,
+        payment_id: &'a str,
+        attempt_id: &'a str,
+        expected_version: i64,
+        payment_attempt_update: PaymentAttemptUpdate,
+    ) -> Result<(), StoreError> {
+        let (owned_merchant_id, owned_payment_id, owned_attempt_id) = (
+            merchant_id.to_owned(),
+            payment_id.to_owned(),
+            attempt_id.to_owned(),
+        );
+        self.mirror(
+            "payment_attempt",
+            "UPDATE",
+            payment_key(merchant_id, payment_id),
+            self.primary.update_attempt(
+                merchant_id,
+                payment_id,
+                attempt_id,
+                expected_version,
+                payment_attempt_update.clone(),
+            ),
+            |secondary| async move {
+                secondary
+                    .update_attempt(
+                        &owned_merchant_id,
+                        &owned_payment_id,
+                        &owned_attempt_id,
+                        expected_version,
+                        payment_attempt_update,
+                    )
+                    .await
+            },
+        )
+        .await
+    }
+
+    async fn update_attempt_if_status<'a>(
+        &self,
+        merchant_id: &'a str,
+        payment_id: &'a str,
+        attempt_id: &'a str,
+        expected_version: i64,
+        expected_status: AttemptStatus,
+        payment_attempt_update: PaymentAttemptUpdate,
+    ) -> Result<(), StoreError> {
+        let (owned_merchant_id, owned_payment_id, owned_attempt_id) = (
+            merchant_id.to_owned(),
+            payment_id.to_owned(),
+            attempt_id.to_owned(),
+        );
+        self.mirror(
+            "payment_attempt",
+            "UPDATE",
+            payment_key(merchant_id, payment_id),
+            self.primary.update_attempt_if_status(
+                merchant_id,
+                payment_id,
+                attempt_id,
+                expected_version,
+                expected_status.clone(),
+                payment_attempt_update.clone(),
+            ),
+            |secondary| async move {
+                secondary
+                    .update_attempt_if_status(
+                        &owned_merchant_id,
+                        &owned_payment_id,
+                        &owned_attempt_id,
+                        expected_version,
+                        expected_status,
+                        payment_attempt_update,
+                    )
+                    .await
+            },
+        )
+        .await
+    }
+}
+
+#[async_trait::async_trait]
+impl PaymentImportInterface for ShadowStore {
+    async fn import_payment(
+        &self,
+        payment_intent: PaymentIntent,
+        payment_attempts: Vec<PaymentAttempt>,
+    ) -> Result<(), StoreError> {
+        self.mirror(
+            "payment_intent",
+            "IMPORT",
+            payment_key(&payment_intent.merchant_id, &payment_intent.payment_id),
+            self.primary
+                .import_payment(payment_intent.clone(), payment_attempts.clone()),
+            |secondary| async move {
+                secondary
+                    .import_payment(payment_intent, payment_attempts)
+                    .await
+            },
+        )
+        .await
+    }
+}
+
+#[async_trait::async_trait]
+impl Init for ShadowStore {
+    async fn prepare(&self) -> Result<(), StoreError> {
+        self.primary.prepare().await?;
+        self.secondary.prepare().await
+    }
+
+    async fn schema_status(&self) -> Result<Vec<MigrationStatus>, StoreError> {
+        self.primary.schema_status().await
+    }
+}
+
+impl StorageInterface for ShadowStore {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::InMemoryStore;
+
+    fn shadow_of(
+        primary: &InMemoryStore,
+        secondary: &InMemoryStore,
+        timeout: Duration,
+    ) -> ShadowStore {
+        ShadowStore {
+            primary: Box::new(primary.clone()),
+            secondary: Box::new(secondary.clone()),
+            timeout,
+            in_flight: Arc::new(DashMap::new()),
+        }
+    }
+
+    /// Whether the secondary ends up holding the same intent and attempts as the primary.
+    async fn converged(
+        primary: &InMemoryStore,
+        secondary: &InMemoryStore,
+        payment_id: &str,
+    ) -> bool {
+        for _ in 0..100 {
+            let primary_payment = (
+                primary.retrieve_intent("m1", payment_id).await.ok(),
+                primary.retrieve_all("m1", payment_id).await.ok(),
+            );
+            let secondary_payment = (
+                secondary.retrieve_intent("m1", payment_id).await.ok(),
+                secondary.retrieve_all("m1", payment_id).await.ok(),
+            );
+            if serde_json::to_value(&primary_payment).unwrap()
+                == serde_json::to_value(&secondary_payment).unwrap()
+            {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn timed_out_secondary_still_writes() {
+        let (primary, secondary) = (InMemoryStore::new(), InMemoryStore::new());
+        let shadow = shadow_of(&primary, &secondary, Duration::ZERO);
+        shadow
+            .create_intent(PaymentIntent::new("p1".to_owned(), "m1".to_owned()))
+            .await
+            .unwrap();
+        primary.retrieve_intent("m1", "p1").await.unwrap();
+        assert!(converged(&primary, &secondary, "p1").await);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn secondary_applies_a_payment_in_call_order() {
+        let (primary, secondary) = (InMemoryStore::new(), InMemoryStore::new());
+        let shadow = shadow_of(&primary, &secondary, Duration::from_secs(1));
+        for payment in 0..50 {
+            let payment_id = format!("p{}", payment);
+            shadow
+                .create_intent(PaymentIntent::new(payment_id.clone(), "m1".to_owned()))
+                .await
+                .unwrap();
+            shadow
+                .create_attempt(PaymentAttempt::new(
+                    payment_id.clone(),
+                    "m1".to_owned(),
+                    "1".to_owned(),
+                ))
+                .await
+                .unwrap();
+            let payment_intent_update = PaymentIntentUpdate {
+                description: Some("updated".to_owned()),
+                ..Default::default()
+            };
+            shadow
+                .update_intent("m1", &payment_id, 2, payment_intent_update)
+                .await
+                .unwrap();
+        }
+        for payment in 0..50 {
+            assert!(converged(&primary, &secondary, &format!("p{}", payment)).await);
+        }
+    }
+}
@@ -1,14 +1,32 @@
+use crate::errors::StoreError;
 use crate::models::*;
+use crate::types::{PaymentAttempt, PaymentIntent};
 use anyhow::Context;
-use fred::prelude::ClientLike;
+use dashmap::DashMap;
+use fred::prelude::{ClientLike, ServerInterface};
+use std::collections::BTreeMap;
 use std::env;
+use std::sync::Arc;
 
 #[cfg(feature = "cassandra")]
 use cassandra_cpp::*;
 
 #[async_trait::async_trait]
 pub trait Init {
-    async fn prepare(&self) -> std::result::Result<(), Box<dyn std::error::Error>>;
+    async fn prepare(&self) -> std::result::Result<(), StoreError>;
+
+    /// Schema migrations known to this build and when they were applied, empty for
+    /// backends without a schema.
+    async fn schema_status(&self) -> std::result::Result<Vec<MigrationStatus>, StoreError> {
+        Ok(Vec::new())
+    }
+}
+
+#[derive(serde::Serialize)]
+pub struct MigrationStatus {
+    pub version: i32,
+    pub name: String,
+    pub applied_at: Option<time::PrimitiveDateTime>,
 }
 
 #[async_trait::async_trait]
@@ -16,6 +34,7 @@ pub trait StorageInterface:
     dyn_clone::DynClone
     + PaymentIntentInterface
     + PaymentAttemptInterface
+    + PaymentImportInterface
     + Send
     + Sync
     + 'static
@@ -37,37 +56,164 @@ impl Clone for App {
 
 impl App {
     pub async fn create_state() -> std::result::Result<Self, Box<dyn std::error::Error>> {
+        let backend = env::var("STORE_BACKEND").unwrap_or(default_backend().to_string());
         Ok(Self {
-            #[cfg(feature = "cassandra")]
-            db: Box::new(CassClient::new().await?),
-
-            #[cfg(feature = "redis")]
-            db: Box::new(RedisClient::new().await?),
+            db: create_backend(backend.as_str()).await?,
         })
     }
 }
 
+/// Backend used when `STORE_BACKEND` is not set, preferring redis when both are compiled in.
+fn default_backend() -> &'static str {
+    if cfg!(feature = "redis") {
+        "redis"
+    } else {
+        "cassandra"
+    }
+}
+
+pub async fn create_backend(
+    backend: &str,
+) -> std::result::Result<Box<dyn StorageInterface>, Box<dyn std::error::Error>> {
+    match backend {
+        #[cfg(feature = "cassandra")]
+        "cassandra" => Ok(Box::new(CassClient::new().await?)),
+
+        #[cfg(feature = "redis")]
+        "redis" => Ok(Box::new(RedisClient::new().await?)),
+
+        #[cfg(feature = "redis")]
+        "hybrid" => Ok(Box::new(crate::drainer::hybrid().await?)),
+
+        "memory" => Ok(Box::new(InMemoryStore::new())),
+
+        "cached" => Ok(Box::new(crate::cache::cached().await?)),
+
+        "shadow" => Ok(Box::new(crate::shadow::shadow().await?)),
+
+        other => Err(format!("STORE_BACKEND {} is not compiled into this binary", other).into()),
+    }
+}
+
+/// Per-request consistency overrides from the `x-cassandra-consistency` and
+/// `x-cassandra-serial-consistency` headers, scoped around each handler.
+#[derive(Clone, Default)]
+#[cfg_attr(not(feature = "cassandra"), allow(dead_code))]
+pub struct RequestConsistency {
+    pub consistency: Option<String>,
+    pub serial_consistency: Option<String>,
+}
+
+tokio::task_local! {
+    pub static REQUEST_CONSISTENCY: RequestConsistency;
+}
+
+#[cfg(feature = "cassandra")]
+fn parse_consistency(level: &str) -> std::result::Result<Consistency, StoreError> {
+    match level.to_uppercase().as_str() {
+        "ANY" => Ok(Consistency::ANY),
+        "ONE" => Ok(Consistency::ONE),
+        "TWO" => Ok(Consistency::TWO),
+        "THREE" => Ok(Consistency::THREE),
+        "QUORUM" => Ok(Consistency::QUORUM),
+        "ALL" => Ok(Consistency::ALL),
+        "LOCAL_QUORUM" => Ok(Consistency::LOCAL_QUORUM),
+        "EACH_QUORUM" => Ok(Consistency::EACH_QUORUM),
+        "SERIAL" => Ok(Consistency::SERIAL),
+        "LOCAL_SERIAL" => Ok(Consistency::LOCAL_SERIAL),
+        "LOCAL_ONE" => Ok(Consistency::LOCAL_ONE),
+        other => Err(StoreError::InvalidRequest(format!(
+            "unknown consistency level {}",
+            other
+        ))),
+    }
+}
+
+/// Consistency and serial consistency per model and operation, keyed as `{model}_{OPERATION}`.
+///
+/// Read from `CASSANDRA_CONSISTENCY_{MODEL}_{OPERATION}` and
+/// `CASSANDRA_SERIAL_CONSISTENCY_{MODEL}_{OPERATION}`,
+/// e.g. `CASSANDRA_CONSISTENCY_PAYMENT_INTENT_FIND=ONE`.
+#[cfg(feature = "cassandra")]
+#[derive(Clone, Default)]
+pub struct ConsistencyConfig {
+    levels: std::collections::HashMap<String, Consistency>,
+    serial_levels: std::collections::HashMap<String, Consistency>,
+}
+
+#[cfg(feature = "cassandra")]
+impl ConsistencyConfig {
+    const MODELS: [&'static str; 2] = ["payment_intent", "payment_attempt"];
+    const OPERATIONS: [&'static str; 4] = ["CREATE", "UPDATE", "FIND", "FIND_ALL"];
+
+    pub fn from_env() -> std::result::Result<Self, StoreError> {
+        let mut config = Self::default();
+        for model in Self::MODELS {
+            for operation in Self::OPERATIONS {
+                let key = format!("{}_{}", model, operation);
+                let suffix = key.to_uppercase();
+                if let Ok(level) = env::var(format!("CASSANDRA_CONSISTENCY_{}", suffix)) {
+                    config.levels.insert(key.clone(), parse_consistency(&level)?);
+                }
+                if let Ok(level) = env::var(format!("CASSANDRA_SERIAL_CONSISTENCY_{}", suffix)) {
+                    config.serial_levels.insert(key, parse_consistency(&level)?);
+                }
+            }
+        }
+        Ok(config)
+    }
+
+    fn consistency(&self, model_name: &str, operation: &str) -> Consistency {
+        self.levels
+            .get(&format!("{}_{}", model_name, operation))
+            .copied()
+            .unwrap_or(match operation {
+                "FIND" | "FIND_ALL" => Consistency::LOCAL_QUORUM,
+                _ => Consistency::ONE,
+            })
+    }
+
+    fn serial_consistency(&self, model_name: &str, operation: &str) -> Option<Consistency> {
+        self.serial_levels
+            .get(&format!("{}_{}", model_name, operation))
+            .copied()
+    }
+}
+
 #[cfg(feature = "cassandra")]
 #[derive(Clone)]
 pub struct CassClient {
     pub cassandra_session: Session,
+    // prepared once per distinct CQL text, update statements are cached per column set
+    pub prepared_statements: Arc<DashMap<String, Arc<PreparedStatement>>>,
+    pub use_prepared: bool,
+    pub consistency: ConsistencyConfig,
+    pub keyspace: crate::cql::Keyspace,
 }
 
 #[cfg(feature = "cassandra")]
 #[async_trait::async_trait]
 impl Init for CassClient {
-    async fn prepare(&self) -> std::result::Result<(), Box<dyn std::error::Error>> {
-        let _ = self
-            .cassandra_session
-            .execute(include_str!("schema.cql"))
-            .await?;
-        Ok(())
+    async fn prepare(&self) -> std::result::Result<(), StoreError> {
+        crate::migrations::migrate(&self.cassandra_session, &self.keyspace).await?;
+        self.prepare_statements().await
+    }
+
+    async fn schema_status(&self) -> std::result::Result<Vec<MigrationStatus>, StoreError> {
+        crate::migrations::status(&self.cassandra_session, &self.keyspace).await
     }
 }
 
 #[async_trait::async_trait]
 impl Init for RedisClient {
-    async fn prepare(&self) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    async fn prepare(&self) -> std::result::Result<(), StoreError> {
+        self.scripts.load(self.pool.next()).await
+    }
+}
+
+#[async_trait::async_trait]
+impl Init for InMemoryStore {
+    async fn prepare(&self) -> std::result::Result<(), StoreError> {
         Ok(())
     }
 }
@@ -75,6 +221,7 @@ impl Init for RedisClient {
 #[cfg(feature = "cassandra")]
 impl StorageInterface for CassClient {}
 impl StorageInterface for RedisClient {}
+impl StorageInterface for InMemoryStore {}
 
 #[cfg(feature = "cassandra")]
 impl CassClient {
@@ -94,7 +241,7 @@ impl CassClient {
             password = env::var("CASSANDRA_PASSWORD").context("CASSANDRA_PASSWORD not found")?;
         }    
         #[cfg(feature = "astra")]{
-            username = "token";
+            username = "token".to_owned();
             password = env::var("ASTRA_DB_APPLICATION_TOKEN").context("astra password not found")?;
 
             // username = env::var("ASTRA_CLIENT_ID").context("astra client id")?;
@@ -113,7 +260,7 @@ impl CassClient {
         #[cfg(feature = "astra")]
         {
             let path_to_cloud_bundle = env::var("ASTRA_CLOUD_BUNDLE_PATH").context("astra cloud bundle path not found")?;
-            cluster.set_cloud_secure_connection_bundle(path_to_cloud_bundle.as_str());
+            cluster.set_cloud_secure_connection_bundle(path_to_cloud_bundle.as_str())?;
 
         }
         
@@ -121,18 +268,200 @@ impl CassClient {
         cluster.set_load_balance_dc_aware::<()>(datacenter.as_str(), 0, false)?;
 
         let session = cluster.connect().await?;
-        Ok(Self {
+        let use_prepared = env::var("CASSANDRA_PREPARED_STATEMENTS")
+            .map(|value| value != "false")
+            .unwrap_or(true);
+        let client = Self {
             cassandra_session: session,
-        })
+            prepared_statements: Arc::new(DashMap::new()),
+            use_prepared,
+            consistency: ConsistencyConfig::from_env()?,
+            keyspace: crate::cql::Keyspace::from_env()?,
+        };
+        // tables may not exist before /init_db, those statements get prepared on first use
+        if let Err(err) = client.prepare_statements().await {
+            eprintln!("statement preparation deferred: {}", err);
+        }
+        Ok(client)
+    }
+
+    /// Prepares the fixed statements up front so the first requests skip the round trip.
+    pub async fn prepare_statements(&self) -> std::result::Result<(), StoreError> {
+        if !self.use_prepared {
+            return Ok(());
+        }
+        for query in cassandra_statements(&self.keyspace.name) {
+            self.prepared(query).await?;
+        }
+        Ok(())
+    }
+
+    async fn prepared(
+        &self,
+        query: String,
+    ) -> std::result::Result<Arc<PreparedStatement>, StoreError> {
+        if let Some(prepared) = self.prepared_statements.get(&query) {
+            return Ok(prepared.clone());
+        }
+        let prepared = Arc::new(self.cassandra_session.prepare(query.as_str()).await?);
+        self.prepared_statements.insert(query, prepared.clone());
+        Ok(prepared)
+    }
+
+    /// Statement for `query`, bound from the prepared cache unless
+    /// `CASSANDRA_PREPARED_STATEMENTS=false` asks for simple statements.
+    pub async fn statement(&self, query: String) -> std::result::Result<Statement, StoreError> {
+        if self.use_prepared {
+            Ok(self.prepared(query).await?.bind())
+        } else {
+            Ok(self.cassandra_session.statement(query))
+        }
+    }
+
+    /// Sets the configured consistency for `operation` on `model_name`, unless the request
+    /// overrides it through headers.
+    pub fn set_consistency(
+        &self,
+        statement: &mut Statement,
+        model_name: &str,
+        operation: &str,
+    ) -> std::result::Result<(), StoreError> {
+        let (consistency, serial_consistency) = self.consistency_levels(model_name, operation)?;
+        statement.set_consistency(consistency)?;
+        if let Some(serial_consistency) = serial_consistency {
+            statement.set_serial_consistency(serial_consistency)?;
+        }
+        Ok(())
+    }
+
+    /// `set_consistency` for a batch, whose own levels replace those of its statements.
+    pub fn set_batch_consistency(
+        &self,
+        batch: &mut Batch,
+        model_name: &str,
+        operation: &str,
+    ) -> std::result::Result<(), StoreError> {
+        let (consistency, serial_consistency) = self.consistency_levels(model_name, operation)?;
+        batch.set_consistency(consistency)?;
+        if let Some(serial_consistency) = serial_consistency {
+            batch.set_serial_consistency(serial_consistency)?;
+        }
+        Ok(())
+    }
+
+    fn consistency_levels(
+        &self,
+        model_name: &str,
+        operation: &str,
+    ) -> std::result::Result<(Consistency, Option<Consistency>), StoreError> {
+        let overrides = REQUEST_CONSISTENCY
+            .try_with(|overrides| overrides.clone())
+            .unwrap_or_default();
+        let consistency = match overrides.consistency {
+            Some(level) => parse_consistency(&level)?,
+            None => self.consistency.consistency(model_name, operation),
+        };
+        let serial_consistency = match overrides.serial_consistency {
+            Some(level) => Some(parse_consistency(&level)?),
+            None => self.consistency.serial_consistency(model_name, operation),
+        };
+        Ok((consistency, serial_consistency))
+    }
+
+    /// `latency_tracker` model label, prepared statements are reported as `{model}_prepared`
+    /// so both modes can be compared on the same dashboard.
+    pub fn latency_label(&self, model_name: &str) -> String {
+        if self.use_prepared {
+            format!("{}_prepared", model_name)
+        } else {
+            model_name.to_owned()
+        }
     }
 
 }
 
+/// What a redis write does when `WAIT` returns fewer acknowledgements than `REDIS_REPLICAS`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DurabilityPolicy {
+    /// Fail the write with `StoreError::Unavailable`, the data is still written on the primary.
+    Fail,
+    /// Log and count the write as under replicated, then report success.
+    Warn,
+    /// Re-issue `WAIT` up to `REDIS_WAIT_RETRIES` times before failing.
+    Retry,
+}
+
+impl std::str::FromStr for DurabilityPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> std::result::Result<Self, Self::Err> {
+        match policy.to_lowercase().as_str() {
+            "fail" => Ok(Self::Fail),
+            "warn" => Ok(Self::Warn),
+            "retry" => Ok(Self::Retry),
+            other => Err(format!("unknown REDIS_DURABILITY_POLICY {}", other)),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct RedisClient {
     pub pool: fred::prelude::RedisPool,
     pub replicas: i64,
     pub timeout: i64,
+    pub durability_policy: DurabilityPolicy,
+    pub wait_retries: u32,
+    pub scripts: RedisScripts,
+    /// Stream every write is also appended to for the drainer, set in hybrid mode.
+    pub drainer_stream: Option<String>,
+}
+
+/// Lua scripts behind every redis write, called by sha through `EVALSHA`.
+///
+/// Records are merged client side and the scripts only check and write the result: redis'
+/// cjson re-encodes numbers as doubles, which would corrupt `i64` amounts such as
+/// `i64::MAX` if the merge happened in lua.
+#[derive(Clone)]
+pub struct RedisScripts {
+    /// Writes a hash field only while it is absent.
+    pub create_if_absent: fred::types::Script,
+    /// Replaces a hash field only while the stored record is at the expected version and,
+    /// for status transitions, still in the expected status.
+    pub versioned_set: fred::types::Script,
+    /// Adds an attempt and the intent pointing at it, or neither.
+    pub create_attempt: fred::types::Script,
+}
+
+impl RedisScripts {
+    fn new() -> Self {
+        Self {
+            create_if_absent: fred::types::Script::from_lua(include_str!(
+                "scripts/create_if_absent.lua"
+            )),
+            versioned_set: fred::types::Script::from_lua(include_str!(
+                "scripts/versioned_set.lua"
+            )),
+            create_attempt: fred::types::Script::from_lua(include_str!(
+                "scripts/create_attempt.lua"
+            )),
+        }
+    }
+
+    /// `SCRIPT LOAD`s every script. A server that later loses them (restart, `SCRIPT FLUSH`,
+    /// failover) is reloaded on the `NOSCRIPT` reply instead.
+    pub async fn load(
+        &self,
+        client: &fred::clients::RedisClient,
+    ) -> std::result::Result<(), StoreError> {
+        for script in [
+            &self.create_if_absent,
+            &self.versioned_set,
+            &self.create_attempt,
+        ] {
+            script.load(client).await?;
+        }
+        Ok(())
+    }
 }
 
 impl RedisClient {
@@ -141,6 +470,9 @@ impl RedisClient {
         let pool_size = env::var("REDIS_POOL_SIZE").context("REDIS POOL size not found")?;
         let timeout = env::var("REDIS_WAIT_TIMEOUT").context("WAIT TIMEOUT not found")?;
         let replicas = env::var("REDIS_REPLICAS").context("Replicas not found")?;
+        let durability_policy =
+            env::var("REDIS_DURABILITY_POLICY").unwrap_or_else(|_| "warn".to_owned());
+        let wait_retries = env::var("REDIS_WAIT_RETRIES").unwrap_or_else(|_| "3".to_owned());
 
         let config = fred::types::RedisConfig::from_url(&connection_url)?;
         let perf = fred::types::PerformanceConfig::default();
@@ -155,13 +487,74 @@ impl RedisClient {
 
         pool.connect();
         pool.wait_for_connect().await?;
-        Ok(Self {
+        let client = Self {
             pool,
             replicas: replicas.parse()?,
             timeout: timeout.parse()?,
-        })
+            durability_policy: durability_policy.parse()?,
+            wait_retries: wait_retries.parse()?,
+            scripts: RedisScripts::new(),
+            drainer_stream: None,
+        };
+        if let Err(err) = client.prepare().await {
+            eprintln!("script loading deferred: {}", err);
+        }
+        Ok(client)
+    }
+
+    /// Blocks on `WAIT` and applies the durability policy to the number of replicas that acked.
+    /// `WAIT` only covers writes made on its own connection, so pass the client that wrote.
+    pub async fn wait_for_replicas(
+        &self,
+        client: &fred::clients::RedisClient,
+        model_name: &str,
+    ) -> std::result::Result<(), StoreError> {
+        let mut attempts = 0;
+        loop {
+            let acked = client.wait::<i64>(self.replicas, self.timeout).await?;
+            if acked >= self.replicas {
+                return Ok(());
+            }
+
+            metrics::counter!(
+                "under_replicated_writes",
+                &[
+                    ("model", model_name.to_string()),
+                    ("policy", format!("{:?}", self.durability_policy)),
+                ]
+            )
+            .increment(1);
+            let message = format!(
+                "write acknowledged by {} of {} replicas",
+                acked, self.replicas
+            );
+            match self.durability_policy {
+                DurabilityPolicy::Warn => {
+                    eprintln!("\"{}\" {}", model_name, message);
+                    return Ok(());
+                }
+                DurabilityPolicy::Retry if attempts < self.wait_retries => attempts += 1,
+                DurabilityPolicy::Retry | DurabilityPolicy::Fail => {
+                    return Err(StoreError::Unavailable(message))
+                }
+            }
+        }
+    }
+}
+/// Process local store backed by concurrent maps, for tests and local development.
+#[derive(Clone, Default)]
+pub struct InMemoryStore {
+    pub intents: Arc<DashMap<String, PaymentIntent>>,
+    // attempts of a payment keyed by attempt_id, payments are keyed by merchant and payment_id
+    pub attempts: Arc<DashMap<String, BTreeMap<String, PaymentAttempt>>>,
+}
+
+impl InMemoryStore {
+    pub fn new() -> Self {
+        Self::default()
     }
 }
+
 // struct RedisClient{
 //     redis_client: String
 // }
@@ -0,0 +1,419 @@
+use crate::errors::StoreError;
+use crate::models::payment_key;
+use crate::store::{create_backend, RedisClient, StorageInterface};
+use crate::types::*;
+use fred::prelude::ClientLike;
+use futures::{StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::path::PathBuf;
+
+#[cfg(feature = "cassandra")]
+use crate::models::PaymentAttemptInterface;
+#[cfg(feature = "cassandra")]
+use crate::store::CassClient;
+#[cfg(feature = "cassandra")]
+use std::sync::Mutex;
+
+/// `store copy` settings, read from `COPY_*`.
+pub struct CopyConfig {
+    /// Payments (redis) or token ranges (cassandra) copied at the same time.
+    pub parallelism: usize,
+    /// Keys asked of each `SCAN` or rows of each token range query.
+    pub page_size: u32,
+    /// Slices the cassandra token ring is cut into, the unit of parallelism and checkpointing.
+    #[cfg_attr(not(feature = "cassandra"), allow(dead_code))]
+    pub token_ranges: u32,
+    pub checkpoint: PathBuf,
+}
+
+impl CopyConfig {
+    pub fn from_env(source: &str, target: &str) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            parallelism: env::var("COPY_PARALLELISM")
+                .unwrap_or_else(|_| "16".to_owned())
+                .parse()?,
+            page_size: env::var("COPY_PAGE_SIZE")
+                .unwrap_or_else(|_| "500".to_owned())
+                .parse()?,
+            token_ranges: env::var("COPY_TOKEN_RANGES")
+                .unwrap_or_else(|_| "256".to_owned())
+                .parse()?,
+            checkpoint: env::var("COPY_CHECKPOINT")
+                .unwrap_or_else(|_| format!("copy_{}_{}.checkpoint", source, target))
+                .into(),
+        })
+    }
+}
+
+/// How far a copy got, rewritten after every page so `store copy ... resume` continues from it.
+/// Pages that were being written when the copy stopped are copied again, which imports allow.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "source", rename_all = "snake_case")]
+pub enum Checkpoint {
+    /// Cursor of the next `SCAN` page, `None` once the scan is complete.
+    Redis { cursor: Option<String>, copied: u64 },
+    /// What is left of each token range.
+    Cassandra {
+        ranges: Vec<TokenRange>,
+        copied: u64,
+    },
+}
+
+/// Tokens in `(after, end]`, done once `after` reaches `end`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TokenRange {
+    pub after: i64,
+    pub end: i64,
+}
+
+impl Checkpoint {
+    fn load(path: &PathBuf) -> Result<Self, Box<dyn Error>> {
+        let checkpoint = std::fs::read(path)
+            .map_err(|err| format!("reading checkpoint {}: {}", path.display(), err))?;
+        Ok(serde_json::from_slice(&checkpoint)?)
+    }
+
+    /// Written next to the checkpoint and renamed over it, so a crash leaves the old or the
+    /// new one and never half of each.
+    fn save(&self, path: &PathBuf) -> Result<(), Box<dyn Error>> {
+        let staging = path.with_extension("tmp");
+        std::fs::write(&staging, serde_json::to_vec(self)?)?;
+        std::fs::rename(staging, path)?;
+        Ok(())
+    }
+
+    #[cfg_attr(not(feature = "cassandra"), allow(dead_code))]
+    fn copied(&self) -> u64 {
+        match self {
+            Self::Redis { copied, .. } | Self::Cassandra { copied, .. } => *copied,
+        }
+    }
+}
+
+/// `store copy <source> <target> [resume]`: streams every intent with its attempts out of
+/// `source` and imports them into `target`, any backend `STORE_BACKEND` accepts.
+pub async fn copy(source: &str, target: &str, resume: bool) -> Result<(), Box<dyn Error>> {
+    if source == target {
+        return Err("source and target are the same backend".into());
+    }
+    let config = CopyConfig::from_env(source, target)?;
+    let checkpoint = if resume {
+        Some(Checkpoint::load(&config.checkpoint)?)
+    } else {
+        None
+    };
+    let target = create_backend(target).await?;
+
+    let copied = match source {
+        "redis" => {
+            let (cursor, copied) = match checkpoint {
+                None => (Some("0".to_owned()), 0),
+                Some(Checkpoint::Redis { cursor, copied }) => {
+                    println!("resuming after {} payments", copied);
+                    (cursor, copied)
+                }
+                Some(_) => return Err("checkpoint was not written by a redis copy".into()),
+            };
+            let source = RedisClient::new().await?;
+            copy_from_redis(source, target.as_ref(), &config, cursor, copied).await?
+        }
+        #[cfg(feature = "cassandra")]
+        "cassandra" => {
+            let checkpoint = match checkpoint {
+                None => Checkpoint::Cassandra {
+                    ranges: token_ranges(config.token_ranges),
+                    copied: 0,
+                },
+                Some(checkpoint @ Checkpoint::Cassandra { .. }) => {
+                    println!("resuming after {} payments", checkpoint.copied());
+                    checkpoint
+                }
+                Some(_) => return Err("checkpoint was not written by a cassandra copy".into()),
+            };
+            copy_from_cassandra(
+                CassClient::new().await?,
+                target.as_ref(),
+                &config,
+                checkpoint,
+            )
+            .await?
+        }
+        other => {
+            return Err(format!(
+                "cannot copy from {}, sources are redis and cassandra",
+                other
+            )
+            .into())
+        }
+    };
+    println!("copied {} payments", copied);
+    Ok(())
+}
+
+/// Walks `SCAN` over the payment hashes, writing each page before checkpointing its cursor.
+async fn copy_from_redis(
+    source: RedisClient,
+    target: &dyn StorageInterface,
+    config: &CopyConfig,
+    mut cursor: Option<String>,
+    mut copied: u64,
+) -> Result<u64, Box<dyn Error>> {
+    while let Some(current) = cursor {
+        let client = source.pool.next();
+        let (next, keys) = scan_payments(client, current, config.page_size).await?;
+        copied += futures::stream::iter(keys)
+            .map(|key| copy_redis_payment(client, target, key))
+            .buffer_unordered(config.parallelism)
+            .try_fold(0, |copied, imported| async move { Ok(copied + imported) })
+            .await?;
+
+        cursor = Some(next).filter(|next| next != "0");
+        Checkpoint::Redis {
+            cursor: cursor.clone(),
+            copied,
+        }
+        .save(&config.checkpoint)?;
+        println!("copied {} payments", copied);
+    }
+    Ok(copied)
+}
+
+/// One `SCAN` page of payment hash keys with the cursor of the next one, `0` after the last.
+async fn scan_payments(
+    client: &fred::clients::RedisClient,
+    cursor: String,
+    page_size: u32,
+) -> Result<(String, Vec<String>), StoreError> {
+    Ok(client
+        .custom::<(String, Vec<String>), _>(
+            fred::types::CustomCommand::new_static("SCAN", None, false),
+            vec![
+                cursor,
+                "MATCH".to_owned(),
+                "mer_*_pay_*".to_owned(),
+                "COUNT".to_owned(),
+                page_size.to_string(),
+            ],
+        )
+        .await?)
+}
+
+/// Imports the payment stored in the hash at `key`, 0 when it has no intent.
+async fn copy_redis_payment(
+    client: &fred::clients::RedisClient,
+    target: &dyn StorageInterface,
+    key: String,
+) -> Result<u64, StoreError> {
+    use fred::prelude::HashesInterface;
+
+    let fields = client
+        .hgetall::<HashMap<String, Vec<u8>>, _>(key.as_str())
+        .await?;
+    let mut payment_intent = None;
+    let mut payment_attempts = Vec::new();
+    for (field, value) in fields {
+        if field.starts_with("pi_") {
+            payment_intent = Some(serde_json::from_slice::<PaymentIntent>(&value)?);
+        } else if field.starts_with("pa_") {
+            payment_attempts.push(serde_json::from_slice::<PaymentAttempt>(&value)?);
+        }
+    }
+    match payment_intent {
+        Some(payment_intent) => {
+            target
+                .import_payment(payment_intent, payment_attempts)
+                .await?;
+            Ok(1)
+        }
+        None => {
+            eprintln!("skipping {}, it holds no payment intent", key);
+            Ok(0)
+        }
+    }
+}
+
+/// `store migrate redis-keys`: moves every payment hash still under the older
+/// `mer_{merchant_id}_pay_{payment_id}` key to `payment_key`. Run it once every instance
+/// writes the new keys; it only moves hashes, so an interrupted run is simply started again.
+pub async fn rekey_redis() -> Result<(), Box<dyn Error>> {
+    let config = CopyConfig::from_env("redis", "redis")?;
+    let source = RedisClient::new().await?;
+    let mut cursor = "0".to_owned();
+    let mut moved = 0;
+    loop {
+        let client = source.pool.next();
+        let (next, keys) = scan_payments(client, cursor, config.page_size).await?;
+        moved += futures::stream::iter(keys)
+            .map(|key| rekey_redis_payment(client, key))
+            .buffer_unordered(config.parallelism)
+            .try_fold(0, |moved, rekeyed| async move { Ok(moved + rekeyed) })
+            .await?;
+        if next == "0" {
+            break;
+        }
+        cursor = next;
+    }
+    println!("moved {} payments to the current key layout", moved);
+    Ok(())
+}
+
+/// Moves the hash at `key` to the key its intent belongs under, 0 when it is already there.
+/// Fields already written under the new key are newer and kept.
+async fn rekey_redis_payment(
+    client: &fred::clients::RedisClient,
+    key: String,
+) -> Result<u64, StoreError> {
+    use fred::prelude::{HashesInterface, KeysInterface};
+
+    let fields = client
+        .hgetall::<HashMap<String, Vec<u8>>, _>(key.as_str())
+        .await?;
+    let payment_intent = match fields.iter().find(|(field, _)| field.starts_with("pi_")) {
+        Some((_, value)) => serde_json::from_slice::<PaymentIntent>(value)?,
+        None => {
+            eprintln!("skipping {}, it holds no payment intent", key);
+            return Ok(0);
+        }
+    };
+    let target = payment_key(&payment_intent.merchant_id, &payment_intent.payment_id);
+    if target == key {
+        return Ok(0);
+    }
+    for (field, value) in fields {
+        client
+            .hsetnx::<(), _, _, _>(target.as_str(), field, value.as_slice())
+            .await?;
+    }
+    client.del::<(), _>(key.as_str()).await?;
+    Ok(1)
+}
+
+/// Cuts the murmur3 token ring into `count` contiguous ranges.
+#[cfg(feature = "cassandra")]
+fn token_ranges(count: u32) -> Vec<TokenRange> {
+    let count = i128::from(count.max(1));
+    let width = (i128::from(i64::MAX) - i128::from(i64::MIN)) / count;
+    (0..count)
+        .map(|index| TokenRange {
+            after: (i128::from(i64::MIN) + index * width) as i64,
+            end: if index + 1 == count {
+                i64::MAX
+            } else {
+                (i128::from(i64::MIN) + (index + 1) * width) as i64
+            },
+        })
+        .collect()
+}
+
+/// Copies the unfinished token ranges, `parallelism` of them at a time.
+#[cfg(feature = "cassandra")]
+async fn copy_from_cassandra(
+    source: CassClient,
+    target: &dyn StorageInterface,
+    config: &CopyConfig,
+    checkpoint: Checkpoint,
+) -> Result<u64, Box<dyn Error>> {
+    let pending = match &checkpoint {
+        Checkpoint::Cassandra { ranges, .. } => ranges
+            .iter()
+            .enumerate()
+            .filter(|(_, range)| range.after < range.end)
+            .map(|(index, range)| (index, range.clone()))
+            .collect::<Vec<_>>(),
+        Checkpoint::Redis { .. } => Vec::new(),
+    };
+    let checkpoint = Mutex::new(checkpoint);
+    futures::stream::iter(pending)
+        .map(|(index, range)| copy_token_range(&source, target, config, &checkpoint, index, range))
+        .buffer_unordered(config.parallelism)
+        .try_collect::<Vec<_>>()
+        .await?;
+    let copied = checkpoint
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .copied();
+    Ok(copied)
+}
+
+/// Pages through one token range, importing each intent with its attempts and recording the
+/// last token written.
+#[cfg(feature = "cassandra")]
+async fn copy_token_range(
+    source: &CassClient,
+    target: &dyn StorageInterface,
+    config: &CopyConfig,
+    checkpoint: &Mutex<Checkpoint>,
+    index: usize,
+    mut range: TokenRange,
+) -> Result<(), Box<dyn Error>> {
+    while range.after < range.end {
+        let page = scan_intents(source, &range, config.page_size).await?;
+        let imported = page.len() as u64;
+        let last_token = page.last().map(|(token, _)| *token);
+        for (_, payment_intent) in page {
+            let payment_attempts = source
+                .retrieve_all(&payment_intent.merchant_id, &payment_intent.payment_id)
+                .await?;
+            target
+                .import_payment(payment_intent, payment_attempts)
+                .await?;
+        }
+        range.after = match last_token {
+            Some(token) if imported == u64::from(config.page_size) => token,
+            _ => range.end,
+        };
+
+        let mut checkpoint = checkpoint.lock().unwrap_or_else(|err| err.into_inner());
+        if let Checkpoint::Cassandra { ranges, copied } = &mut *checkpoint {
+            ranges[index] = range.clone();
+            *copied += imported;
+        }
+        checkpoint.save(&config.checkpoint)?;
+        if range.after == range.end {
+            println!(
+                "copied {} payments, token range {} done",
+                checkpoint.copied(),
+                index
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Up to `page_size` intents with their partition token, in token order.
+#[cfg(feature = "cassandra")]
+async fn scan_intents(
+    source: &CassClient,
+    range: &TokenRange,
+    page_size: u32,
+) -> Result<Vec<(i64, PaymentIntent)>, StoreError> {
+    use crate::cql::CqlModel;
+    use cassandra_cpp::{BindRustType, LendingIterator};
+
+    let mut statement = source
+        .statement(PaymentIntent::scan_cql(&source.keyspace.name))
+        .await?;
+    statement.bind(0, range.after)?;
+    statement.bind(1, range.end)?;
+    statement.bind(2, page_size as i32)?;
+    source.set_consistency(&mut statement, "payment_intent", "FIND_ALL")?;
+    let rows = crate::utils::time_wrapper(
+        statement.execute(),
+        &source.latency_label("payment_intent"),
+        "SCAN",
+    )
+    .await?;
+
+    let mut page = Vec::new();
+    let mut rows = rows.iter();
+    while let Some(row) = rows.next() {
+        page.push((
+            row.get_column(0)?.get_i64()?,
+            PaymentIntent::from_row(&row)?,
+        ));
+    }
+    Ok(page)
+}
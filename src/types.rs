@@ -1,9 +1,66 @@
+use crate::errors::StoreError;
 use serde::{Deserialize, Serialize};
 use time::PrimitiveDateTime;
 
-#[derive(Serialize, Deserialize)]
-pub struct PaymentAttempt {
+/// Defines a model struct together with its cassandra mapping: the table, its primary key and
+/// one column per field, so the CQL, bindings, row decoding and DDL all follow the struct.
+/// A field written `name: type = value` decodes a null column as `value` instead of failing.
+macro_rules! cql_model {
+    (
+        $(#[$meta:meta])*
+        pub struct $model:ident in $table:literal
+            partition_key($($partition:ident),+) clustering_key($($clustering:ident),*)
+        {
+            $($(#[$field_meta:meta])* pub $field:ident : $ty:ty $(= $null:expr)?),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        pub struct $model {
+            $($(#[$field_meta])* pub $field: $ty,)*
+        }
+
+        #[cfg(feature = "cassandra")]
+        impl crate::cql::CqlModel for $model {
+            const TABLE: &'static str = $table;
+            const PARTITION_KEY: &'static [&'static str] = &[$(stringify!($partition)),+];
+            const CLUSTERING_KEY: &'static [&'static str] = &[$(stringify!($clustering)),*];
+
+            fn columns() -> Vec<(&'static str, &'static str)> {
+                vec![$((stringify!($field), <$ty as crate::cql::CqlColumn>::CQL_TYPE)),*]
+            }
+
+            fn populate_statement(
+                &self,
+                stmt: &mut cassandra_cpp::Statement,
+            ) -> Result<usize, StoreError> {
+                let mut loc = 0;
+                $(
+                    crate::cql::CqlColumn::bind(&self.$field, stmt, loc)?;
+                    loc += 1;
+                )*
+                Ok(loc)
+            }
+
+            fn from_row(row: &cassandra_cpp::Row) -> Result<Self, StoreError> {
+                Ok(Self {
+                    $($field: crate::cql::from_column_or(
+                        row,
+                        stringify!($field),
+                        None $(.or(Some($null)))?,
+                    )?,)*
+                })
+            }
+        }
+    };
+}
+
+cql_model! {
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PaymentAttempt in "payment_attempts"
+    partition_key(payment_id, merchant_id) clustering_key(attempt_id)
+{
     pub payment_id: String,
+    #[serde(default)]
     pub merchant_id: String,
     pub attempt_id: String,
     pub status: AttemptStatus,
@@ -22,7 +79,9 @@ pub struct PaymentAttempt {
     pub capture_on: Option<time::PrimitiveDateTime>,
     pub confirm: bool,
     pub authentication_type: Option<AuthenticationType>,
+    #[serde(default = "crate::utils::now")]
     pub created_at: PrimitiveDateTime,
+    #[serde(default = "crate::utils::now")]
     pub modified_at: PrimitiveDateTime,
     pub last_synced: Option<PrimitiveDateTime>,
     pub cancellation_reason: Option<String>,
@@ -46,6 +105,7 @@ pub struct PaymentAttempt {
     // reference to the payment at connector side
     pub connector_response_reference_id: Option<String>,
     pub amount_capturable: i64,
+    #[serde(default = "crate::utils::default_updated_by")]
     pub updated_by: String,
     pub merchant_connector_id: Option<String>,
     pub authentication_data: Option<serde_json::Value>,
@@ -62,86 +122,39 @@ pub struct PaymentAttempt {
     pub charge_id: Option<String>,
     pub client_source: Option<String>,
     pub client_version: Option<String>,
+    // bumped by every update, which must name the version it was based on, rows written
+    // before the column was added hold null and read as 0
+    #[serde(default)]
+    pub version: i64 = 0,
+}
 }
-
-#[cfg(feature = "cassandra")]
-use cassandra_cpp::{BindRustType, Statement};
 
 impl PaymentAttempt {
-    #[cfg(feature = "cassandra")]
-    pub fn populate_statement(
-        &self,
-        stmt: &mut Statement,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        stmt.bind(0, self.payment_id.as_str())?;
-        stmt.bind(1, self.merchant_id.as_str())?;
-        stmt.bind(2, self.attempt_id.as_str())?;
-        stmt.bind(3, enum_parse(&self.status)?.as_str())?;
-        stmt.bind(4, self.amount)?;
-        for_opt(stmt, &self.currency, 5)?;
-        e_for_opt(stmt, &self.save_to_locker, 6)?;
-
-        opt_string(stmt, &self.connector, 7)?;
-        opt_string(stmt, &self.error_message, 8)?;
-        e_for_opt(stmt, &self.offer_amount, 9)?;
-        e_for_opt(stmt, &self.surcharge_amount, 10)?;
-        e_for_opt(stmt, &self.tax_amount, 11)?;
-        opt_string(stmt, &self.payment_method_id, 12)?;
-        for_opt(stmt, &self.payment_method, 13)?;
-        opt_string(stmt, &self.connector_transaction_id, 14)?;
-        for_opt(stmt, &self.capture_method, 15)?;
-        for_opt(stmt, &self.capture_on, 16)?;
-        stmt.bind(17, self.confirm)?;
-        for_opt(stmt, &self.authentication_type, 18)?;
-        stmt.bind(19, enum_parse(&self.created_at)?.as_str())?;
-        stmt.bind(20, enum_parse(&self.modified_at)?.as_str())?;
-        for_opt(stmt, &self.last_synced, 21)?;
-
-        opt_string(stmt, &self.cancellation_reason, 22)?;
-        e_for_opt(stmt, &self.amount_to_capture, 23)?;
-        opt_string(stmt, &self.mandate_id, 24)?;
-        for_opt(stmt, &self.browser_info, 25)?;
-        opt_string(stmt, &self.error_code, 26)?;
-        opt_string(stmt, &self.payment_token, 27)?;
-        for_opt(stmt, &self.connector_metadata, 28)?;
-        for_opt(stmt, &self.payment_experience, 29)?;
-        for_opt(stmt, &self.payment_method_type, 30)?;
-        for_opt(stmt, &self.payment_method_data, 31)?;
-        opt_string(stmt, &self.business_sub_label, 32)?;
-
-        for_opt(stmt, &self.straight_through_algorithm, 33)?;
-        opt_string(stmt, &self.preprocessing_step_id, 34)?;
-        for_opt(stmt, &self.mandate_details, 35)?;
-        opt_string(stmt, &self.error_reason, 36)?;
-        e_for_opt(stmt, &self.multiple_capture_count, 37)?;
-        opt_string(stmt, &self.connector_response_reference_id, 38)?;
-        stmt.bind(39, self.amount_capturable)?;
-        stmt.bind(40, self.updated_by.as_str())?;
-        opt_string(stmt, &self.merchant_connector_id, 41)?;
-        for_opt(stmt, &self.authentication_data, 42)?;
-        opt_string(stmt, &self.encoded_data, 43)?;
-        opt_string(stmt, &self.unified_code, 44)?;
-        opt_string(stmt, &self.unified_message, 45)?;
-        e_for_opt(stmt, &self.net_amount, 46)?;
-        e_for_opt(stmt, &self.external_three_ds_authentication_attempted, 47)?;
-        opt_string(stmt, &self.authentication_connector, 48)?;
-        opt_string(stmt, &self.authentication_id, 49)?;
-        for_opt(stmt, &self.mandate_data, 50)?;
-        opt_string(stmt, &self.fingerprint_id, 51)?;
-        opt_string(stmt, &self.payment_method_billing_address_id, 52)?;
-        opt_string(stmt, &self.charge_id, 53)?;
-
-        opt_string(stmt, &self.client_source, 54)?;
-        opt_string(stmt, &self.client_version, 55)?;
-
+    pub fn validate(&self) -> Result<(), String> {
+        if self.payment_id.is_empty() || self.merchant_id.is_empty() || self.attempt_id.is_empty() {
+            return Err("payment_id, merchant_id and attempt_id are required".to_owned());
+        }
+        if self.amount < 0 || self.amount_capturable < 0 {
+            return Err("amount cannot be negative".to_owned());
+        }
+        if self
+            .amount_to_capture
+            .is_some_and(|amount| amount > self.amount)
+        {
+            return Err("amount_to_capture cannot exceed amount".to_owned());
+        }
         Ok(())
     }
 
-    pub fn new(i: String, version: String) -> Self {
+    pub fn attempt_id_for(payment_id: &str, version: &str) -> String {
+        format!("attempt_{}_{}", payment_id, version)
+    }
+
+    pub fn new(i: String, merchant_id: String, version: String) -> Self {
         Self {
             payment_id: i.clone(),
-            merchant_id: "kaps".to_owned(),
-            attempt_id: format!("attempt_{}_{}", &i, version),
+            merchant_id,
+            attempt_id: Self::attempt_id_for(&i, &version),
             status: AttemptStatus::AuthenticationFailed,
             amount: i64::MAX,
             currency: Some(Currency::USD),
@@ -202,15 +215,20 @@ impl PaymentAttempt {
             charge_id: Some("randomeString12412953w23421".to_owned()),
             client_source: Some("randomeString12412953w23421".to_owned()),
             client_version: Some("randomeString12412953w23421".to_owned()),
+            version: 1,
         }
     }
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
-pub struct PaymentIntent {
+cql_model! {
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct PaymentIntent in "payment_intents"
+    partition_key(payment_id, merchant_id) clustering_key()
+{
     pub payment_id: String,
+    #[serde(default)]
     pub merchant_id: String,
-    pub status: String,
+    pub status: IntentStatus,
     pub amount: i64,
     pub currency: Option<Currency>,
     pub amount_captured: Option<i64>,
@@ -223,12 +241,15 @@ pub struct PaymentIntent {
     pub billing_address_id: Option<String>,
     pub statement_descriptor_name: Option<String>,
     pub statement_descriptor_suffix: Option<String>,
+    #[serde(default = "crate::utils::now")]
     pub created_at: PrimitiveDateTime,
+    #[serde(default = "crate::utils::now")]
     pub modified_at: PrimitiveDateTime,
     pub last_synced: Option<PrimitiveDateTime>,
     pub setup_future_usage: Option<String>,
     pub off_session: Option<bool>,
     pub client_secret: Option<String>,
+    #[serde(default)]
     pub active_attempt_id: String,
     pub business_country: Option<String>,
     pub business_label: Option<String>,
@@ -236,6 +257,7 @@ pub struct PaymentIntent {
     pub allowed_payment_method_types: Option<serde_json::Value>,
     pub connector_metadata: Option<serde_json::Value>,
     pub feature_metadata: Option<serde_json::Value>,
+    #[serde(default)]
     pub attempt_count: i16,
     pub profile_id: Option<String>,
     // Denotes the action(approve or reject) taken by merchant in case of manual review.
@@ -244,6 +266,7 @@ pub struct PaymentIntent {
     pub payment_link_id: Option<String>,
     pub payment_confirm_source: Option<String>,
 
+    #[serde(default = "crate::utils::default_updated_by")]
     pub updated_by: String,
     pub surcharge_applicable: Option<bool>,
     pub request_incremental_authorization: Option<String>,
@@ -254,14 +277,46 @@ pub struct PaymentIntent {
     pub request_external_three_ds_authentication: Option<bool>,
     pub charges: Option<serde_json::Value>,
     pub frm_metadata: Option<serde_json::Value>,
+    // bumped by every update, which must name the version it was based on, rows written
+    // before the column was added hold null and read as 0
+    #[serde(default)]
+    pub version: i64 = 0,
+}
 }
 
 impl PaymentIntent {
-    pub fn new(i: String) -> Self {
+    /// Columns `record_attempt` changes besides the version, in the order cassandra binds them.
+    #[cfg(feature = "cassandra")]
+    pub const ATTEMPT_COLUMNS: &'static [&'static str] = &["active_attempt_id", "attempt_count"];
+
+    /// Points the intent at a newly created attempt, written together with the attempt.
+    pub fn record_attempt(&mut self, attempt_id: &str) {
+        self.active_attempt_id = attempt_id.to_owned();
+        self.attempt_count = self.attempt_count.saturating_add(1);
+        self.version += 1;
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.payment_id.is_empty() || self.merchant_id.is_empty() {
+            return Err("payment_id and merchant_id are required".to_owned());
+        }
+        if self.amount < 0 {
+            return Err("amount cannot be negative".to_owned());
+        }
+        if self
+            .amount_captured
+            .is_some_and(|amount| amount > self.amount)
+        {
+            return Err("amount_captured cannot exceed amount".to_owned());
+        }
+        Ok(())
+    }
+
+    pub fn new(i: String, merchant_id: String) -> Self {
         PaymentIntent {
             payment_id: i.clone(),
-            merchant_id: "kaps".to_string(),
-            status: "Processing".to_string(),
+            merchant_id,
+            status: IntentStatus::Processing,
             amount: 1234_i64,
             currency: Some(Currency::USD),
             amount_captured: None,
@@ -280,7 +335,8 @@ impl PaymentIntent {
             setup_future_usage: Some(String::from("OffSession")),
             off_session: Some(false),
             client_secret: Some("randomeString12412953w23421".to_owned()),
-            active_attempt_id: "asdasdas".to_string(),
+            // set when the first attempt is created
+            active_attempt_id: String::new(),
 
             business_country: None,
             business_label: Some("randomeString12412953w23421".to_owned()),
@@ -288,7 +344,7 @@ impl PaymentIntent {
             allowed_payment_method_types: None, //Value
             connector_metadata: Some(get_large_value()), //Value
             feature_metadata: Some(get_large_value()), //Value
-            attempt_count: i16::MAX,
+            attempt_count: 0,
             profile_id: Some("randomeString12412953w23421".to_owned()),
             merchant_decision: Some("randomeString12412953w23421".to_owned()),
             payment_link_id: Some("randomeString12412953w23421".to_owned()),
@@ -304,59 +360,181 @@ impl PaymentIntent {
             request_external_three_ds_authentication: Some(false),
             charges: None,      //Option<pii::SecretSerdeValue>,
             frm_metadata: None, //Option<pii::SecretSerdeValue>,
+            version: 1,
         }
     }
+}
 
-    #[cfg(feature = "cassandra")]
-    pub fn populate_statement(
-        &self,
-        stmt: &mut Statement,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        stmt.bind(0, self.payment_id.as_str())?;
-        stmt.bind(1, self.merchant_id.as_str())?;
-        stmt.bind(2, self.status.as_str())?;
-        #[cfg(feature = "cassandra")]
-        stmt.bind(3, self.amount)?;
-        for_opt(stmt, &self.currency, 4)?;
-        e_for_opt(stmt, &self.amount_captured, 5)?;
-        opt_string(stmt, &self.customer_id, 6)?;
-        opt_string(stmt, &self.description, 7)?;
-        opt_string(stmt, &self.return_url, 8)?;
-        for_opt(stmt, &self.metadata, 9)?;
-        opt_string(stmt, &self.connector_id, 10)?;
-        opt_string(stmt, &self.shipping_address_id, 11)?;
-        opt_string(stmt, &self.billing_address_id, 12)?;
-        opt_string(stmt, &self.statement_descriptor_name, 13)?;
-        opt_string(stmt, &self.statement_descriptor_suffix, 14)?;
-        for_opt(stmt, &Some(self.created_at), 15)?;
-        for_opt(stmt, &Some(self.modified_at), 16)?;
-        for_opt(stmt, &self.last_synced, 17)?;
-        opt_string(stmt, &self.setup_future_usage, 18)?;
-        e_for_opt(stmt, &self.off_session, 19)?;
-        opt_string(stmt, &self.client_secret, 20)?;
-        stmt.bind(21, self.active_attempt_id.as_str())?;
-        opt_string(stmt, &self.business_country, 22)?;
-        opt_string(stmt, &self.business_label, 23)?;
-        for_opt(stmt, &self.order_details, 24)?;
-        for_opt(stmt, &self.allowed_payment_method_types, 25)?;
-        for_opt(stmt, &self.connector_metadata, 26)?;
-        for_opt(stmt, &self.feature_metadata, 27)?;
-        stmt.bind(28, self.attempt_count)?;
-        opt_string(stmt, &self.profile_id, 29)?;
-        opt_string(stmt, &self.merchant_decision, 30)?;
-        opt_string(stmt, &self.payment_link_id, 31)?;
-        opt_string(stmt, &self.payment_confirm_source, 32)?;
-        stmt.bind(33, self.updated_by.as_str())?;
-        e_for_opt(stmt, &self.surcharge_applicable, 34)?;
-        opt_string(stmt, &self.request_incremental_authorization, 35)?;
-        e_for_opt(stmt, &self.incremental_authorization_allowed, 36)?;
-        e_for_opt(stmt, &self.authorization_count, 37)?;
-        for_opt(stmt, &self.session_expiry, 38)?;
-        opt_string(stmt, &self.fingerprint_id, 39)?;
-        e_for_opt(stmt, &self.request_external_three_ds_authentication, 40)?;
-        for_opt(stmt, &self.charges, 41)?;
-        for_opt(stmt, &self.frm_metadata, 42)?;
-        Ok(())
+/// Generates a patch struct for a model where every set field overwrites the
+/// column of the same name, along with the CQL columns and bindings for it.
+macro_rules! model_update {
+    ($name:ident for $model:ident { $($field:ident : $ty:ty),* $(,)? }) => {
+        #[derive(Clone, Default, Serialize, Deserialize)]
+        pub struct $name {
+            $(
+                #[serde(default, skip_serializing_if = "Option::is_none")]
+                pub $field: Option<$ty>,
+            )*
+        }
+
+        impl $name {
+            pub fn apply(self, record: &mut $model) {
+                $(
+                    if let Some(val) = self.$field {
+                        record.$field = val.into();
+                    }
+                )*
+            }
+
+            /// Columns touched by this update, in binding order.
+            pub fn columns(&self) -> Vec<&'static str> {
+                let mut columns = Vec::new();
+                $(
+                    if self.$field.is_some() {
+                        columns.push(stringify!($field));
+                    }
+                )*
+                columns
+            }
+
+            pub fn is_empty(&self) -> bool {
+                self.columns().is_empty()
+            }
+
+            /// Binds the set fields starting at index 0 and returns the next free index.
+            #[cfg(feature = "cassandra")]
+            pub fn populate_statement(
+                &self,
+                stmt: &mut cassandra_cpp::Statement,
+            ) -> Result<usize, StoreError> {
+                let mut loc = 0;
+                $(
+                    if let Some(val) = &self.$field {
+                        crate::cql::CqlColumn::bind(val, stmt, loc)?;
+                        loc += 1;
+                    }
+                )*
+                Ok(loc)
+            }
+        }
+    };
+}
+
+model_update!(PaymentAttemptUpdate for PaymentAttempt {
+    status: AttemptStatus,
+    amount: i64,
+    currency: Currency,
+    save_to_locker: bool,
+    connector: String,
+    error_message: String,
+    offer_amount: i64,
+    surcharge_amount: i64,
+    tax_amount: i64,
+    payment_method_id: String,
+    payment_method: PaymentMethod,
+    connector_transaction_id: String,
+    capture_method: CaptureMethod,
+    capture_on: PrimitiveDateTime,
+    confirm: bool,
+    authentication_type: AuthenticationType,
+    modified_at: PrimitiveDateTime,
+    last_synced: PrimitiveDateTime,
+    cancellation_reason: String,
+    amount_to_capture: i64,
+    mandate_id: String,
+    browser_info: serde_json::Value,
+    error_code: String,
+    payment_token: String,
+    connector_metadata: serde_json::Value,
+    payment_experience: PaymentExperience,
+    payment_method_type: PaymentMethodType,
+    payment_method_data: serde_json::Value,
+    business_sub_label: String,
+    straight_through_algorithm: serde_json::Value,
+    preprocessing_step_id: String,
+    mandate_details: MandateDataType,
+    error_reason: String,
+    multiple_capture_count: i16,
+    connector_response_reference_id: String,
+    amount_capturable: i64,
+    updated_by: String,
+    merchant_connector_id: String,
+    authentication_data: serde_json::Value,
+    encoded_data: String,
+    unified_code: String,
+    unified_message: String,
+    net_amount: i64,
+    external_three_ds_authentication_attempted: bool,
+    authentication_connector: String,
+    authentication_id: String,
+    mandate_data: MandateDetails,
+    fingerprint_id: String,
+    payment_method_billing_address_id: String,
+    charge_id: String,
+    client_source: String,
+    client_version: String,
+});
+
+model_update!(PaymentIntentUpdate for PaymentIntent {
+    status: IntentStatus,
+    amount: i64,
+    currency: Currency,
+    amount_captured: i64,
+    customer_id: String,
+    description: String,
+    return_url: String,
+    metadata: serde_json::Value,
+    connector_id: String,
+    shipping_address_id: String,
+    billing_address_id: String,
+    statement_descriptor_name: String,
+    statement_descriptor_suffix: String,
+    modified_at: PrimitiveDateTime,
+    last_synced: PrimitiveDateTime,
+    setup_future_usage: String,
+    off_session: bool,
+    client_secret: String,
+    active_attempt_id: String,
+    business_country: String,
+    business_label: String,
+    order_details: Vec<serde_json::Value>,
+    allowed_payment_method_types: serde_json::Value,
+    connector_metadata: serde_json::Value,
+    feature_metadata: serde_json::Value,
+    attempt_count: i16,
+    profile_id: String,
+    merchant_decision: String,
+    payment_link_id: String,
+    payment_confirm_source: String,
+    updated_by: String,
+    surcharge_applicable: bool,
+    request_incremental_authorization: String,
+    incremental_authorization_allowed: bool,
+    authorization_count: i32,
+    session_expiry: PrimitiveDateTime,
+    fingerprint_id: String,
+    request_external_three_ds_authentication: bool,
+    charges: serde_json::Value,
+    frm_metadata: serde_json::Value,
+});
+
+impl PaymentAttemptUpdate {
+    /// Rejects a status change the transition table does not allow from `current`.
+    pub fn check_transition(&self, current: &AttemptStatus) -> Result<(), StoreError> {
+        match &self.status {
+            Some(next) => current.check_transition(next),
+            None => Ok(()),
+        }
+    }
+}
+
+impl PaymentIntentUpdate {
+    /// Rejects a status change the transition table does not allow from `current`.
+    pub fn check_transition(&self, current: &IntentStatus) -> Result<(), StoreError> {
+        match &self.status {
+            Some(next) => current.check_transition(next),
+            None => Ok(()),
+        }
     }
 }
 
@@ -411,58 +589,93 @@ pub fn get_large_value() -> serde_json::Value {
     })
 }
 
-fn enum_parse<T: serde::Serialize>(em: &T) -> Result<String, Box<dyn std::error::Error>> {
-    Ok(serde_json::to_string(em)?)
-}
-
-#[cfg(feature = "cassandra")]
-pub fn for_opt<T: serde::Serialize>(
-    stat: &mut Statement,
-    data: &Option<T>,
-    loc: usize,
-) -> Result<(), Box<dyn std::error::Error>>
-where
-{
-    match data {
-        Some(val) => stat.bind(loc, enum_parse(val)?.as_str())?,
-        None => stat.bind_null(loc)?,
-    };
+/// A status whose legal moves are listed in a transition table, checked by every backend
+/// before it writes an update that changes the status.
+pub trait StatusTransitions: PartialEq + std::fmt::Debug + Sized + 'static {
+    /// Statuses reachable in one step. Staying in the current status is always allowed.
+    fn transitions(&self) -> &'static [Self];
 
-    Ok(())
+    fn check_transition(&self, next: &Self) -> Result<(), StoreError> {
+        if self == next || self.transitions().contains(next) {
+            return Ok(());
+        }
+        Err(StoreError::InvalidTransition(format!(
+            "status cannot move from {:?} to {:?}",
+            self, next
+        )))
+    }
 }
 
-#[cfg(feature = "cassandra")]
-fn opt_string(
-    stat: &mut Statement,
-    data: &Option<String>,
-    loc: usize,
-) -> Result<(), Box<dyn std::error::Error>> {
-    match data {
-        Some(val) => stat.bind(loc, val.as_str())?,
-        None => stat.bind_null(loc)?,
-    };
-
-    Ok(())
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum IntentStatus {
+    // written by earlier builds of the benchmark
+    #[serde(alias = "SUCCESS")]
+    Succeeded,
+    Failed,
+    Cancelled,
+    Processing,
+    RequiresCustomerAction,
+    RequiresMerchantAction,
+    RequiresPaymentMethod,
+    RequiresConfirmation,
+    RequiresCapture,
+    PartiallyCaptured,
+    PartiallyCapturedAndCapturable,
 }
 
-#[cfg(feature = "cassandra")]
-fn e_for_opt<T: Clone>(
-    stat: &mut Statement,
-    data: &Option<T>,
-    loc: usize,
-) -> Result<(), Box<dyn std::error::Error>>
-where
-    cassandra_cpp::Statement: BindRustType<T>,
-{
-    match data {
-        Some(val) => stat.bind(loc, val.clone())?,
-        None => stat.bind_null(loc)?,
-    };
-
-    Ok(())
+impl StatusTransitions for IntentStatus {
+    fn transitions(&self) -> &'static [Self] {
+        use IntentStatus::*;
+        match self {
+            RequiresPaymentMethod => &[
+                RequiresConfirmation,
+                Processing,
+                RequiresCustomerAction,
+                Cancelled,
+                Failed,
+            ],
+            RequiresConfirmation => &[
+                RequiresPaymentMethod,
+                Processing,
+                RequiresCustomerAction,
+                Cancelled,
+                Failed,
+            ],
+            Processing => &[
+                RequiresPaymentMethod,
+                RequiresCustomerAction,
+                RequiresMerchantAction,
+                RequiresCapture,
+                PartiallyCaptured,
+                PartiallyCapturedAndCapturable,
+                Succeeded,
+                Cancelled,
+                Failed,
+            ],
+            RequiresCustomerAction => &[
+                RequiresPaymentMethod,
+                Processing,
+                RequiresCapture,
+                Succeeded,
+                Cancelled,
+                Failed,
+            ],
+            RequiresMerchantAction => &[Processing, RequiresCapture, Succeeded, Cancelled, Failed],
+            RequiresCapture => &[
+                Processing,
+                PartiallyCaptured,
+                PartiallyCapturedAndCapturable,
+                Succeeded,
+                Cancelled,
+                Failed,
+            ],
+            PartiallyCapturedAndCapturable => &[Processing, PartiallyCaptured, Succeeded],
+            Succeeded | Failed | Cancelled | PartiallyCaptured => &[],
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum AttemptStatus {
     Started,
     AuthenticationFailed,
@@ -490,7 +703,132 @@ pub enum AttemptStatus {
     DeviceDataCollectionPending,
 }
 
-#[derive(Serialize, Deserialize)]
+impl StatusTransitions for AttemptStatus {
+    fn transitions(&self) -> &'static [Self] {
+        use AttemptStatus::*;
+        match self {
+            Started => &[
+                PaymentMethodAwaited,
+                ConfirmationAwaited,
+                DeviceDataCollectionPending,
+                AuthenticationPending,
+                AuthenticationSuccessful,
+                AuthenticationFailed,
+                RouterDeclined,
+                Authorizing,
+                Authorized,
+                AuthorizationFailed,
+                Charged,
+                PartialCharged,
+                PartialChargedAndChargeable,
+                CodInitiated,
+                Pending,
+                Unresolved,
+                Voided,
+                Failure,
+            ],
+            PaymentMethodAwaited => &[
+                Started,
+                ConfirmationAwaited,
+                AuthenticationPending,
+                Authorizing,
+                Pending,
+                Voided,
+                Failure,
+            ],
+            ConfirmationAwaited => &[
+                Started,
+                AuthenticationPending,
+                Authorizing,
+                Authorized,
+                Charged,
+                Pending,
+                Voided,
+                Failure,
+            ],
+            DeviceDataCollectionPending => &[
+                AuthenticationPending,
+                AuthenticationSuccessful,
+                AuthenticationFailed,
+                Authorizing,
+                Failure,
+            ],
+            AuthenticationPending => &[
+                DeviceDataCollectionPending,
+                AuthenticationSuccessful,
+                AuthenticationFailed,
+                Voided,
+                Failure,
+            ],
+            AuthenticationSuccessful => &[
+                Authorizing,
+                Authorized,
+                AuthorizationFailed,
+                Charged,
+                PartialCharged,
+                PartialChargedAndChargeable,
+                Pending,
+                Failure,
+            ],
+            Authorizing | Pending => &[
+                AuthenticationPending,
+                Authorized,
+                AuthorizationFailed,
+                Charged,
+                PartialCharged,
+                PartialChargedAndChargeable,
+                Pending,
+                Unresolved,
+                Voided,
+                Failure,
+            ],
+            Unresolved => &[
+                Authorized,
+                AuthorizationFailed,
+                Charged,
+                PartialCharged,
+                Voided,
+                Failure,
+            ],
+            Authorized => &[
+                CaptureInitiated,
+                CaptureFailed,
+                Charged,
+                PartialCharged,
+                PartialChargedAndChargeable,
+                VoidInitiated,
+                VoidFailed,
+                Voided,
+                AutoRefunded,
+            ],
+            CaptureInitiated => &[
+                CaptureFailed,
+                Charged,
+                PartialCharged,
+                PartialChargedAndChargeable,
+            ],
+            CaptureFailed => &[CaptureInitiated, Charged, VoidInitiated, Voided],
+            PartialChargedAndChargeable => &[
+                CaptureInitiated,
+                CaptureFailed,
+                Charged,
+                PartialCharged,
+                VoidInitiated,
+                Voided,
+                AutoRefunded,
+            ],
+            VoidInitiated => &[VoidFailed, Voided],
+            VoidFailed => &[VoidInitiated, Voided, CaptureInitiated, Charged],
+            CodInitiated => &[Charged, Voided, Failure],
+            Charged | PartialCharged => &[AutoRefunded],
+            AuthenticationFailed | RouterDeclined | AuthorizationFailed | Voided
+            | AutoRefunded | Failure => &[],
+        }
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Serialize, Deserialize)]
 pub enum Currency {
     AED,
     ALL,
@@ -639,7 +977,7 @@ pub enum Currency {
     ZMW,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub enum PaymentMethod {
     Card,
     Token,
@@ -654,7 +992,7 @@ pub enum PaymentMethod {
     ProcessorToken,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub enum CaptureMethod {
     /// Post the payment authorization, the capture will be executed on the full amount immediately
     Automatic,
@@ -666,7 +1004,7 @@ pub enum CaptureMethod {
     Scheduled,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub enum AuthenticationType {
     /// If the card is enrolled for 3DS authentication, the 3DS based authentication will be activated. The liability of chargeback shift to the issuer
     ThreeDs,
@@ -674,7 +1012,7 @@ pub enum AuthenticationType {
     NoThreeDs,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub enum PaymentExperience {
     /// The URL to which the customer needs to be redirected for completing the payment.
     RedirectToUrl,
@@ -692,7 +1030,7 @@ pub enum PaymentExperience {
     DisplayWaitScreen,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub enum PaymentMethodType {
     Ach,
     Affirm,
@@ -789,13 +1127,13 @@ pub enum PaymentMethodType {
     Mifinity,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub enum MandateDataType {
     SingleUse(MandateAmountData),
     MultiUse(Option<MandateAmountData>),
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct MandateAmountData {
     pub amount: i64,
     pub currency: Currency,
@@ -804,7 +1142,7 @@ pub struct MandateAmountData {
     pub metadata: Option<serde_json::Value>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct MandateDetails {
     pub update_mandate_id: Option<String>,
 }
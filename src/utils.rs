@@ -8,6 +8,15 @@ where
     let result = func.await;
     let time_spent = start.elapsed();
     println!("\"{}\" {} {}" , model_name, op,time_spent.as_micros());
-    metrics::histogram!("latency_tracker", &[("model", model_name.to_string()), ("operation", op.to_string())]).record(time_spent.as_secs_f64() * (1000 as f64));
+    metrics::histogram!("latency_tracker", &[("model", model_name.to_string()), ("operation", op.to_string())]).record(time_spent.as_secs_f64() * 1000_f64);
     result
-}
\ No newline at end of file
+}
+
+pub fn now() -> time::PrimitiveDateTime {
+    let now = time::OffsetDateTime::now_utc();
+    time::PrimitiveDateTime::new(now.date(), now.time())
+}
+
+pub fn default_updated_by() -> String {
+    "store".to_owned()
+}